@@ -0,0 +1,48 @@
+use chippy8::clock::TimerClock;
+use chippy8::instructions::decode;
+use chippy8::machine::{Machine, Platform};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// A busy loop mixing ALU operations, skips, jumps and a sprite draw
+const LOOP: [u16; 8] = [
+    0x7001, // loop: v0 += 1
+    0x8104, // v1 += v0
+    0x8213, // v2 ^= v1
+    0xA250, // i := 0x250
+    0xD011, // sprite v0 v1 1
+    0x4000, // if v0 == 0 then
+    0x7101, //   v1 += 1
+    0x1200, // jump loop
+];
+
+fn looping_machine(decode_cache: bool) -> Machine {
+    let mut machine = Machine::with_seed(Platform::Chip8, 0);
+    machine.timers.set_clock(TimerClock::Frames);
+    machine.load_rom_from_instrhex(&LOOP).unwrap();
+    machine.set_decode_cache_enabled(decode_cache);
+    machine
+}
+
+fn bench_execute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run_frame_1000");
+    for (name, decode_cache) in [("uncached", false), ("cached", true)] {
+        let mut machine = looping_machine(decode_cache);
+        group.bench_function(name, |b| {
+            b.iter(|| machine.run_frame(black_box(1000)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    c.bench_function("decode_all_opcodes", |b| {
+        b.iter(|| {
+            for opcode in 0..=u16::MAX {
+                black_box(decode(black_box(opcode), Platform::XoChip));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_execute, bench_decode);
+criterion_main!(benches);
@@ -0,0 +1,404 @@
+//! Static control-flow analysis of a program, to tell code from data.
+//!
+//! Starting from the entry point, the analysis follows jumps, calls, skips and
+//! returns, like a recursive traversal disassembler. Computed jumps (BNNN)
+//! can't be followed without running the program, so they're reported as
+//! unresolved and whatever they jump to is considered data.
+use crate::disassembler::{decode_at, Line, Syntax};
+use crate::instructions::Instruction;
+use crate::machine::Platform;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// To the next instruction, including after a call returns
+    Fallthrough,
+    Jump,
+    Call,
+    /// Over the next instruction, when a skip instruction's condition holds
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// A sequence of instructions only entered at the top and only left at the
+/// bottom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// Address right after the last instruction
+    pub end: usize,
+    pub lines: Vec<Line>,
+    pub successors: Vec<Edge>,
+}
+
+impl BasicBlock {
+    pub fn last_instruction(&self) -> Option<Instruction> {
+        self.lines.last().and_then(|line| line.instruction)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub entry: usize,
+    /// Address the analyzed program was loaded at
+    pub start_address: usize,
+    /// Basic blocks by start address
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    /// Addresses of the BNNN computed jumps whose targets are unknown
+    pub unresolved_jumps: Vec<usize>,
+    /// Addresses of reachable opcodes that can't be decoded
+    pub unknown_opcodes: Vec<usize>,
+    /// Start addresses of subroutines, i.e. targets of 2NNN
+    pub subroutines: BTreeSet<usize>,
+    /// Whether each byte of the program is part of a reachable instruction
+    code: Vec<bool>,
+}
+
+/// Analyze `program`, loaded at `start_address`, executing from that same
+/// address. Opcodes that `platform` doesn't have are unknown
+pub fn analyze(program: &[u8], start_address: usize, platform: Platform) -> ControlFlowGraph {
+    let in_program =
+        |address: usize| (start_address..start_address + program.len()).contains(&address);
+    let decode = |address: usize| {
+        decode_at(program, address - start_address, platform).map(|line| Line { address, ..line })
+    };
+
+    let mut cfg = ControlFlowGraph {
+        entry: start_address,
+        start_address,
+        blocks: BTreeMap::new(),
+        unresolved_jumps: vec![],
+        unknown_opcodes: vec![],
+        subroutines: BTreeSet::new(),
+        code: vec![false; program.len()],
+    };
+    // Every reachable instruction, with its successors if it ends a block
+    let mut instructions: BTreeMap<usize, (Line, Option<Vec<Edge>>)> = BTreeMap::new();
+    let mut leaders = BTreeSet::from([start_address]);
+    let mut worklist = vec![start_address];
+    while let Some(address) = worklist.pop() {
+        if instructions.contains_key(&address) || !in_program(address) {
+            continue;
+        }
+        let Some(line) = decode(address) else {
+            continue;
+        };
+        let Some(instruction) = line.instruction else {
+            // A lone byte at the very end of the program
+            continue;
+        };
+        let next = address + line.bytes.len();
+        let edge = |kind, target| Edge { target, kind };
+        let successors = match instruction {
+            Instruction::Jump(nnn) => Some(vec![edge(EdgeKind::Jump, nnn as usize)]),
+            Instruction::Subroutine(nnn) => {
+                cfg.subroutines.insert(nnn as usize);
+                Some(vec![
+                    edge(EdgeKind::Call, nnn as usize),
+                    edge(EdgeKind::Fallthrough, next),
+                ])
+            }
+            Instruction::SkipIfEqualRegVal(..)
+            | Instruction::SkipIfNotEqualRegVal(..)
+            | Instruction::SkipIfEqualRegReg(..)
+            | Instruction::SkipIfNotEqualRegReg(..)
+            | Instruction::SkipIfKeyPressed(_)
+            | Instruction::SkipIfKeyNotPressed(_) => {
+                // Skipping over F000 NNNN skips 4 bytes
+                let skipped_len = decode(next).map_or(2, |line| line.bytes.len());
+                Some(vec![
+                    edge(EdgeKind::Fallthrough, next),
+                    edge(EdgeKind::Skip, next + skipped_len),
+                ])
+            }
+            Instruction::JumpWithOffset(_) => {
+                cfg.unresolved_jumps.push(address);
+                Some(vec![])
+            }
+            Instruction::Unknown(_) => {
+                cfg.unknown_opcodes.push(address);
+                Some(vec![])
+            }
+            Instruction::Return | Instruction::Exit => Some(vec![]),
+            _ => None,
+        };
+        match &successors {
+            Some(edges) => {
+                for edge in edges {
+                    leaders.insert(edge.target);
+                    worklist.push(edge.target);
+                }
+            }
+            None => worklist.push(next),
+        }
+        let offset = address - start_address;
+        cfg.code[offset..offset + line.bytes.len()].fill(true);
+        instructions.insert(address, (line, successors));
+    }
+    cfg.unresolved_jumps.sort();
+    cfg.unknown_opcodes.sort();
+
+    for &leader in &leaders {
+        let mut address = leader;
+        let mut block = BasicBlock {
+            start: leader,
+            end: leader,
+            lines: vec![],
+            successors: vec![],
+        };
+        while let Some((line, successors)) = instructions.get(&address) {
+            block.lines.push(line.clone());
+            address += line.bytes.len();
+            if let Some(successors) = successors {
+                block.successors = successors.clone();
+                break;
+            }
+            if leaders.contains(&address) || !instructions.contains_key(&address) {
+                block.successors = vec![Edge {
+                    target: address,
+                    kind: EdgeKind::Fallthrough,
+                }];
+                break;
+            }
+        }
+        block.end = address;
+        if !block.lines.is_empty() {
+            cfg.blocks.insert(leader, block);
+        }
+    }
+    cfg
+}
+
+impl ControlFlowGraph {
+    /// Whether the byte at `address` belongs to a reachable instruction
+    pub fn is_code(&self, address: usize) -> bool {
+        address
+            .checked_sub(self.start_address)
+            .and_then(|offset| self.code.get(offset))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// The ranges of the program that are never executed, typically sprites
+    pub fn data_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for (offset, &is_code) in self.code.iter().enumerate() {
+            let address = self.start_address + offset;
+            if is_code {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == address => range.end += 1,
+                _ => ranges.push(address..address + 1),
+            }
+        }
+        ranges
+    }
+
+    /// The block containing the instruction at `address`
+    pub fn block_containing(&self, address: usize) -> Option<&BasicBlock> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| address < block.end)
+    }
+
+    /// The graph in Graphviz DOT format, with one node per basic block.
+    /// Unresolved computed jumps are shown in red, and edges leaving the
+    /// program are left out since there's no block to point to
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for line in &block.lines {
+                let text = line.text(Syntax::Octo).replace('"', "\\\"");
+                write!(label, "0x{:03X}  {}\\l", line.address, text).unwrap();
+            }
+            let color = if matches!(
+                block.last_instruction(),
+                Some(Instruction::JumpWithOffset(_))
+            ) {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                "    b{:03X} [label=\"{}\"{}];",
+                block.start, label, color
+            )
+            .unwrap();
+            for edge in &block.successors {
+                if !self.blocks.contains_key(&edge.target) {
+                    continue;
+                }
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                };
+                writeln!(
+                    out,
+                    "    b{:03X} -> b{:03X}{};",
+                    block.start, edge.target, style
+                )
+                .unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::rom::ROM_START_ADDRESS;
+
+    fn analyze_source(source: &str) -> (ControlFlowGraph, BTreeMap<String, usize>) {
+        let program = assemble(source).unwrap();
+        (
+            analyze(&program.bytes, ROM_START_ADDRESS, Platform::XoChip),
+            program.labels,
+        )
+    }
+
+    #[test]
+    fn test_blocks_and_data() {
+        let (cfg, labels) = analyze_source(
+            "
+            : main
+                i := sprite-data
+                loop
+                    sprite v0 v1 2
+                    if v0 == 5 then jump done
+                    v0 += 1
+                    helper
+                again
+            : done
+                jump done
+            : helper
+                return
+            : sprite-data
+                0xFF 0x81 0x81 0xFF
+            ",
+        );
+        let starts: Vec<usize> = cfg.blocks.keys().copied().collect();
+        // main, loop body, jump done, v0 += 1, after the call, done, helper
+        assert_eq!(
+            starts,
+            vec![0x200, 0x202, 0x206, 0x208, 0x20C, 0x20E, 0x210]
+        );
+        assert_eq!(cfg.blocks[&0x200].successors[0].kind, EdgeKind::Fallthrough);
+        assert_eq!(
+            cfg.blocks[&0x202].successors,
+            vec![
+                Edge {
+                    target: 0x206,
+                    kind: EdgeKind::Fallthrough
+                },
+                Edge {
+                    target: 0x208,
+                    kind: EdgeKind::Skip
+                },
+            ]
+        );
+        assert_eq!(cfg.subroutines, BTreeSet::from([labels["helper"]]));
+
+        let data = labels["sprite-data"];
+        assert_eq!(cfg.data_ranges(), vec![data..data + 4]);
+        assert!(cfg.is_code(0x201));
+        assert!(!cfg.is_code(data));
+        assert_eq!(cfg.block_containing(0x204).unwrap().start, 0x202);
+    }
+
+    #[test]
+    fn test_computed_jump_is_unresolved() {
+        let (cfg, _) = analyze_source(
+            "
+            : main
+                jump0 table
+            : table
+                jump 0x300
+                jump 0x302
+            ",
+        );
+        assert_eq!(cfg.unresolved_jumps, vec![0x200]);
+        assert!(cfg.blocks[&0x200].successors.is_empty());
+        assert_eq!(cfg.data_ranges(), vec![0x202..0x206]);
+        assert!(cfg
+            .to_dot()
+            .contains("b200 [label=\"0x200  jump0 0x202\\l\", color=red];"));
+    }
+
+    #[test]
+    fn test_unknown_opcodes_per_platform() {
+        let program = assemble(": main hires exit").unwrap().bytes;
+        let cfg = analyze(&program, ROM_START_ADDRESS, Platform::Chip8);
+        assert_eq!(cfg.unknown_opcodes, vec![0x200]);
+        let cfg = analyze(&program, ROM_START_ADDRESS, Platform::SuperChip);
+        assert!(cfg.unknown_opcodes.is_empty());
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let (cfg, _) = analyze_source(
+            "
+            : main
+                v0 := 1
+                if v0 == 1 then v1 := 2
+            : end
+                jump end
+            ",
+        );
+        assert_eq!(
+            cfg.to_dot(),
+            "digraph cfg {
+    node [shape=box, fontname=\"monospace\"];
+    b200 [label=\"0x200  v0 := 0x01\\l0x202  if v0 == 0x01 then\\l\"];
+    b200 -> b204;
+    b200 -> b206 [label=\"skip\"];
+    b204 [label=\"0x204  v1 := 0x02\\l\"];
+    b204 -> b206;
+    b206 [label=\"0x206  jump 0x206\\l\"];
+    b206 -> b206 [label=\"jump\"];
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_to_dot_falling_off_the_end() {
+        let (cfg, _) = analyze_source(
+            "
+            : main
+                if v0 == 1 then jump 0x300
+                v1 := 2
+            ",
+        );
+        assert_eq!(
+            cfg.to_dot(),
+            "digraph cfg {
+    node [shape=box, fontname=\"monospace\"];
+    b200 [label=\"0x200  if v0 == 0x01 then\\l\"];
+    b200 -> b202;
+    b200 -> b204 [label=\"skip\"];
+    b202 [label=\"0x202  jump 0x300\\l\"];
+    b204 [label=\"0x204  v1 := 0x02\\l\"];
+}
+"
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::ops::{Index, IndexMut};
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Array2D<T> {
     data: Vec<T>,
     rows: usize,
@@ -20,6 +20,12 @@ where
         Array2D { data, rows, cols }
     }
 
+    /// An array from its elements, row by row
+    pub fn from_vec(rows: usize, cols: usize, data: Vec<T>) -> Self {
+        assert_eq!(data.len(), rows * cols, "wrong number of elements");
+        Array2D { data, rows, cols }
+    }
+
     pub fn as_slice(&self) -> &[T] {
         self.data.as_slice()
     }
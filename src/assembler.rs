@@ -0,0 +1,821 @@
+//! An assembler for a subset of Octo, see
+//! https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+//!
+//! Supported: labels (`: name`), `:const`, `:alias`, `:call`, `:byte`, all
+//! the instructions in `Instruction` with their Octo mnemonics, `if ... then`,
+//! `loop`/`while`/`again`, and numbers as data bytes, e.g. for sprites.
+//! Like Octo, execution starts at the `main` label: the program begins with a
+//! `jump main`, left out when `main` is the very first label.
+use crate::instructions::Instruction;
+use crate::rom::ROM_START_ADDRESS;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    /// 1-based line of the offending token
+    pub line: usize,
+    /// 1-based column of the offending token
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// The output of the assembler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The ROM, to be loaded at `ROM_START_ADDRESS`
+    pub bytes: Vec<u8>,
+    /// Address of every label
+    pub labels: BTreeMap<String, usize>,
+}
+
+/// Assemble Octo source code into a ROM
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source),
+        position: 0,
+        end: end_position(source),
+        // Reserved for `jump main`, see `finish`
+        bytes: vec![0; 2],
+        labels: BTreeMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: vec![],
+        loops: vec![],
+    };
+    while assembler.position < assembler.tokens.len() {
+        assembler.statement()?;
+    }
+    assembler.finish()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut rest = line;
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let len = rest[start..]
+                .find(char::is_whitespace)
+                .unwrap_or(rest.len() - start);
+            let offset = line.len() - rest.len() + start;
+            tokens.push(Token {
+                text: &rest[start..start + len],
+                line: i + 1,
+                column: line[..offset].chars().count() + 1,
+            });
+            rest = &rest[start + len..];
+        }
+    }
+    tokens
+}
+
+/// Where errors about a missing token at the end of the source are reported
+fn end_position(source: &str) -> (usize, usize) {
+    let last_line = source.lines().last().unwrap_or("");
+    (source.lines().count().max(1), last_line.chars().count() + 1)
+}
+
+/// Where an address operand must be written once its label is known
+#[derive(Debug, Clone, Copy)]
+enum FixupKind {
+    /// The 12 least significant bits of the opcode at the offset
+    Nnn,
+    /// The 16-bit word at the offset, for `i := long`
+    Long,
+}
+
+struct Fixup<'a> {
+    offset: usize,
+    kind: FixupKind,
+    label: Token<'a>,
+}
+
+/// An open `loop`, with the `while` jumps to patch with its end
+struct Loop<'a> {
+    token: Token<'a>,
+    address: usize,
+    breaks: Vec<usize>,
+}
+
+/// What `if` and `while` test
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    EqualVal(u8, u8),
+    NotEqualVal(u8, u8),
+    EqualReg(u8, u8),
+    NotEqualReg(u8, u8),
+    KeyPressed(u8),
+    KeyNotPressed(u8),
+}
+
+impl Condition {
+    fn negate(self) -> Condition {
+        use Condition::*;
+        match self {
+            EqualVal(x, nn) => NotEqualVal(x, nn),
+            NotEqualVal(x, nn) => EqualVal(x, nn),
+            EqualReg(x, y) => NotEqualReg(x, y),
+            NotEqualReg(x, y) => EqualReg(x, y),
+            KeyPressed(x) => KeyNotPressed(x),
+            KeyNotPressed(x) => KeyPressed(x),
+        }
+    }
+
+    /// The instruction skipping the next one unless the condition holds
+    fn skip_unless(self) -> Instruction {
+        use Condition::*;
+        match self {
+            EqualVal(x, nn) => Instruction::SkipIfNotEqualRegVal(x, nn),
+            NotEqualVal(x, nn) => Instruction::SkipIfEqualRegVal(x, nn),
+            EqualReg(x, y) => Instruction::SkipIfNotEqualRegReg(x, y),
+            NotEqualReg(x, y) => Instruction::SkipIfEqualRegReg(x, y),
+            KeyPressed(x) => Instruction::SkipIfKeyNotPressed(x),
+            KeyNotPressed(x) => Instruction::SkipIfKeyPressed(x),
+        }
+    }
+}
+
+struct Assembler<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    end: (usize, usize),
+    bytes: Vec<u8>,
+    labels: BTreeMap<String, usize>,
+    constants: HashMap<&'a str, i64>,
+    aliases: HashMap<&'a str, u8>,
+    fixups: Vec<Fixup<'a>>,
+    loops: Vec<Loop<'a>>,
+}
+
+fn error<T>(token: Token, message: impl Into<String>) -> Result<T, AssembleError> {
+    Err(AssembleError {
+        line: token.line,
+        column: token.column,
+        message: message.into(),
+    })
+}
+
+/// The operators that can follow a register at the start of a statement
+const REGISTER_OPERATORS: [&str; 9] = [":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<="];
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v' | 'V'), Some(digit), None) => digit.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+impl<'a> Assembler<'a> {
+    fn address(&self) -> usize {
+        ROM_START_ADDRESS + self.bytes.len()
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, AssembleError> {
+        match self.tokens.get(self.position) {
+            Some(&token) => {
+                self.position += 1;
+                Ok(token)
+            }
+            None => Err(AssembleError {
+                line: self.end.0,
+                column: self.end.1,
+                message: "unexpected end of file".to_string(),
+            }),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        if token.text != text {
+            return error(
+                token,
+                format!("expected '{}', found '{}'", text, token.text),
+            );
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.bytes.extend(instruction.encode().to_be_bytes());
+    }
+
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        self.register_from(token)
+    }
+
+    fn register_from(&self, token: Token) -> Result<u8, AssembleError> {
+        match parse_register(token.text).or_else(|| self.aliases.get(token.text).copied()) {
+            Some(register) => Ok(register),
+            None => error(
+                token,
+                format!("expected a register, found '{}'", token.text),
+            ),
+        }
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        parse_register(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    /// A number or a constant
+    fn value(&mut self) -> Result<(Token<'a>, i64), AssembleError> {
+        let token = self.next()?;
+        match parse_number(token.text).or_else(|| self.constants.get(token.text).copied()) {
+            Some(value) => Ok((token, value)),
+            None => error(token, format!("expected a number, found '{}'", token.text)),
+        }
+    }
+
+    fn value_in_range(&mut self, min: i64, max: i64) -> Result<i64, AssembleError> {
+        let (token, value) = self.value()?;
+        if value < min || value > max {
+            return error(
+                token,
+                format!("{} is out of range, expected {} to {}", value, min, max),
+            );
+        }
+        Ok(value)
+    }
+
+    /// A byte, which can also be given as a negative number
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        Ok(self.value_in_range(-128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        Ok(self.value_in_range(0, 15)? as u8)
+    }
+
+    /// Emit an instruction whose operand is an address, which is either a
+    /// number, a constant or a label, possibly defined later
+    fn emit_with_address(
+        &mut self,
+        instruction: fn(u16) -> Instruction,
+    ) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        let address = match parse_number(token.text)
+            .or_else(|| self.constants.get(token.text).copied())
+            .or_else(|| self.labels.get(token.text).map(|&a| a as i64))
+        {
+            Some(address) => address,
+            None => {
+                self.fixups.push(Fixup {
+                    offset: self.bytes.len(),
+                    kind: FixupKind::Nnn,
+                    label: token,
+                });
+                0
+            }
+        };
+        if !(0..=0xFFF).contains(&address) {
+            return error(
+                token,
+                format!("address {:#x} doesn't fit in 12 bits", address),
+            );
+        }
+        self.emit(instruction(address as u16));
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AssembleError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        match operator.text {
+            "key" => return Ok(Condition::KeyPressed(x)),
+            "-key" => return Ok(Condition::KeyNotPressed(x)),
+            "==" | "!=" => {}
+            _ => {
+                return error(
+                    operator,
+                    format!("unsupported condition '{}'", operator.text),
+                )
+            }
+        }
+        let equal = operator.text == "==";
+        if self.peek().is_some_and(|text| self.is_register(text)) {
+            let y = self.register()?;
+            Ok(if equal {
+                Condition::EqualReg(x, y)
+            } else {
+                Condition::NotEqualReg(x, y)
+            })
+        } else {
+            let nn = self.byte()?;
+            Ok(if equal {
+                Condition::EqualVal(x, nn)
+            } else {
+                Condition::NotEqualVal(x, nn)
+            })
+        }
+    }
+
+    fn define_label(&mut self, name: Token<'a>) -> Result<(), AssembleError> {
+        if parse_number(name.text).is_some() || self.is_register(name.text) {
+            return error(name, format!("'{}' can't be used as a name", name.text));
+        }
+        if self.labels.contains_key(name.text) || self.constants.contains_key(name.text) {
+            return error(name, format!("'{}' is already defined", name.text));
+        }
+        // Nothing to jump over when main comes first
+        if name.text == "main" && self.bytes.len() == 2 && self.labels.is_empty() {
+            self.bytes.clear();
+        }
+        self.labels.insert(name.text.to_string(), self.address());
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), AssembleError> {
+        use Instruction::*;
+        let token = self.next()?;
+        match token.text {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                if self.labels.contains_key(name.text) || self.constants.contains_key(name.text) {
+                    return error(name, format!("'{}' is already defined", name.text));
+                }
+                let (_, value) = self.value()?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":call" => self.emit_with_address(Subroutine)?,
+            ":byte" => {
+                let byte = self.byte()?;
+                self.bytes.push(byte);
+            }
+            "return" | ";" => self.emit(Return),
+            "clear" => self.emit(ClearScreen),
+            "exit" => self.emit(Exit),
+            "hires" => self.emit(HighRes),
+            "lores" => self.emit(LowRes),
+            "scroll-left" => self.emit(ScrollLeft),
+            "scroll-right" => self.emit(ScrollRight),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(ScrollDown(n));
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(ScrollUp(n));
+            }
+            "plane" => {
+                let n = self.value_in_range(0, 3)? as u8;
+                self.emit(SelectPlanes(n));
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(ConvertToDecimal(x));
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(SaveFlags(x));
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(LoadFlags(x));
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let save = token.text == "save";
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    self.emit(if save {
+                        SaveRange(x, y)
+                    } else {
+                        LoadRange(x, y)
+                    });
+                } else {
+                    self.emit(if save {
+                        RegistersToMemory(x)
+                    } else {
+                        MemoryToRegisters(x)
+                    });
+                }
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Display(x, y, n));
+            }
+            "jump" => self.emit_with_address(Jump)?,
+            "jump0" => self.emit_with_address(JumpWithOffset)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(if token.text == "delay" {
+                    SetDelayTimer(x)
+                } else {
+                    SetSoundTimer(x)
+                });
+            }
+            "i" => self.index_statement()?,
+            "if" => {
+                let condition = self.condition()?;
+                self.expect("then")?;
+                self.emit(condition.skip_unless());
+                self.statement()?;
+            }
+            "loop" => self.loops.push(Loop {
+                token,
+                address: self.address(),
+                breaks: vec![],
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                if self.loops.is_empty() {
+                    return error(token, "'while' outside of a loop");
+                }
+                self.emit(condition.negate().skip_unless());
+                let offset = self.bytes.len();
+                self.loops.last_mut().unwrap().breaks.push(offset);
+                self.emit(Jump(0));
+            }
+            "again" => {
+                let Some(open_loop) = self.loops.pop() else {
+                    return error(token, "'again' without 'loop'");
+                };
+                self.emit(Jump(open_loop.address as u16));
+                let end = self.address() as u16;
+                for offset in open_loop.breaks {
+                    self.bytes[offset..offset + 2]
+                        .copy_from_slice(&Jump(end).encode().to_be_bytes());
+                }
+            }
+            text if self.is_register(text) => self.register_statement(token)?,
+            text if parse_number(text).is_some() || self.constants.contains_key(text) => {
+                self.position -= 1;
+                let byte = self.byte()?;
+                self.bytes.push(byte);
+            }
+            text if text.starts_with(':') => {
+                return error(token, format!("unsupported directive '{}'", text));
+            }
+            _ if self
+                .peek()
+                .is_some_and(|text| REGISTER_OPERATORS.contains(&text)) =>
+            {
+                return error(
+                    token,
+                    format!("expected a register, found '{}'", token.text),
+                );
+            }
+            _ => {
+                // A bare name is a call to that label
+                self.position -= 1;
+                self.emit_with_address(Subroutine)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), AssembleError> {
+        let operator = self.next()?;
+        match operator.text {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Instruction::FontCharacter(x));
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Instruction::BigFontCharacter(x));
+                }
+                Some("long") => {
+                    self.next()?;
+                    self.emit(Instruction::LoadLongIndex);
+                    let token = self.next()?;
+                    let address = match parse_number(token.text)
+                        .or_else(|| self.constants.get(token.text).copied())
+                        .or_else(|| self.labels.get(token.text).map(|&a| a as i64))
+                    {
+                        Some(address) => address,
+                        None => {
+                            self.fixups.push(Fixup {
+                                offset: self.bytes.len(),
+                                kind: FixupKind::Long,
+                                label: token,
+                            });
+                            0
+                        }
+                    };
+                    if !(0..=0xFFFF).contains(&address) {
+                        return error(
+                            token,
+                            format!("address {:#x} doesn't fit in 16 bits", address),
+                        );
+                    }
+                    self.bytes.extend((address as u16).to_be_bytes());
+                }
+                _ => self.emit_with_address(Instruction::SetIndexRegister)?,
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddToIndex(x));
+            }
+            _ => {
+                return error(
+                    operator,
+                    format!("unsupported operator '{}' for i", operator.text),
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, register: Token<'a>) -> Result<(), AssembleError> {
+        use Instruction::*;
+        let x = self.register_from(register)?;
+        let operator = self.next()?;
+        let operand_is_register = self.peek().is_some_and(|text| self.is_register(text));
+        let instruction = match (operator.text, operand_is_register) {
+            (":=", true) => Set(x, self.register()?),
+            (":=", false) => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    Random(x, self.byte()?)
+                }
+                Some("key") => {
+                    self.next()?;
+                    GetKey(x)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    ReadDelayTimer(x)
+                }
+                _ => SetRegToVal(x, self.byte()?),
+            },
+            ("+=", true) => Add(x, self.register()?),
+            ("+=", false) => AddValToReg(x, self.byte()?),
+            ("-=", true) => SubtractXY(x, self.register()?),
+            ("-=", false) => AddValToReg(x, self.byte()?.wrapping_neg()),
+            ("=-", true) => SubtractYX(x, self.register()?),
+            ("|=", true) => Or(x, self.register()?),
+            ("&=", true) => And(x, self.register()?),
+            ("^=", true) => Xor(x, self.register()?),
+            (">>=", true) => ShiftRight(x, self.register()?),
+            ("<<=", true) => ShiftLeft(x, self.register()?),
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", false) => {
+                let operand = self.next()?;
+                return error(
+                    operand,
+                    format!("expected a register, found '{}'", operand.text),
+                );
+            }
+            _ => {
+                return error(
+                    operator,
+                    format!("unsupported operator '{}' for a register", operator.text),
+                )
+            }
+        };
+        self.emit(instruction);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Program, AssembleError> {
+        if let Some(open_loop) = self.loops.pop() {
+            return error(open_loop.token, "'loop' without 'again'");
+        }
+        for fixup in &self.fixups {
+            let Some(&address) = self.labels.get(fixup.label.text) else {
+                return error(
+                    fixup.label,
+                    format!("undefined name '{}'", fixup.label.text),
+                );
+            };
+            let word = &mut self.bytes[fixup.offset..fixup.offset + 2];
+            match fixup.kind {
+                FixupKind::Nnn => {
+                    if address > 0xFFF {
+                        return error(
+                            fixup.label,
+                            format!("address {:#x} doesn't fit in 12 bits", address),
+                        );
+                    }
+                    word[0] |= (address >> 8) as u8;
+                    word[1] = address as u8;
+                }
+                FixupKind::Long => word.copy_from_slice(&(address as u16).to_be_bytes()),
+            }
+        }
+        match self.labels.get("main") {
+            None => {
+                return Err(AssembleError {
+                    line: self.end.0,
+                    column: self.end.1,
+                    message: "missing 'main' label".to_string(),
+                })
+            }
+            Some(&main) if main == ROM_START_ADDRESS => {}
+            Some(&main) if main > 0xFFF => {
+                return Err(AssembleError {
+                    line: self.end.0,
+                    column: self.end.1,
+                    message: format!("main at {:#x} doesn't fit in 12 bits", main),
+                })
+            }
+            Some(&main) => self.bytes[..2]
+                .copy_from_slice(&Instruction::Jump(main as u16).encode().to_be_bytes()),
+        }
+        Ok(Program {
+            bytes: self.bytes,
+            labels: self.labels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_words(source: &str) -> Vec<u16> {
+        assemble(source)
+            .unwrap()
+            .bytes
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn test_instructions() {
+        let source = "
+            : main
+            clear
+            v0 := 0x12  va := v3  v1 := random 0xF0  v2 := key  v3 := delay
+            v0 += 1  v0 += v1  v0 -= 1  v0 -= v1  v0 =- v1
+            v0 |= v1  v0 &= v1  v0 ^= v1  v0 >>= v1  v0 <<= v1
+            i := 0x300  i := hex v1  i := bighex v1  i += v2
+            delay := v4  buzzer := v5
+            sprite v1 v2 15  bcd v3  save v4  load v5  save v1 - v2  load v2 - v1
+            saveflags v7  loadflags v7  plane 3
+            hires lores scroll-down 4 scroll-up 2 scroll-left scroll-right
+            jump 0x456  jump0 0x300  :call 0x400  return  ;  exit
+        ";
+        assert_eq!(
+            assemble_words(source),
+            vec![
+                0x00E0, 0x6012, 0x8A30, 0xC1F0, 0xF20A, 0xF307, 0x7001, 0x8014, 0x70FF, 0x8015,
+                0x8017, 0x8011, 0x8012, 0x8013, 0x8016, 0x801E, 0xA300, 0xF129, 0xF130, 0xF21E,
+                0xF415, 0xF518, 0xD12F, 0xF333, 0xF455, 0xF565, 0x5122, 0x5213, 0xF775, 0xF785,
+                0xF301, 0x00FF, 0x00FE, 0x00C4, 0x00D2, 0x00FC, 0x00FB, 0x1456, 0xB300, 0x2400,
+                0x00EE, 0x00EE, 0x00FD,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_constants_and_aliases() {
+        let source = "
+            :const SPEED 3
+            :alias x v1
+            : main
+                x := SPEED
+                i := sprite-data   # forward reference
+                draw
+                jump main
+            : draw
+                sprite x x 2
+                return
+            : sprite-data
+                0b11000000 0x80
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program.bytes,
+            vec![
+                0x61, 0x03, 0xA2, 0x0C, 0x22, 0x08, 0x12, 0x00, 0xD1, 0x12, 0x00, 0xEE, 0xC0, 0x80
+            ]
+        );
+        assert_eq!(program.labels["main"], 0x200);
+        assert_eq!(program.labels["draw"], 0x208);
+        assert_eq!(program.labels["sprite-data"], 0x20C);
+    }
+
+    #[test]
+    fn test_jump_to_main() {
+        let program = assemble(": sub v1 := 2 return : main v0 := 1 sub loop again").unwrap();
+        assert_eq!(program.labels["sub"], 0x202);
+        assert_eq!(program.labels["main"], 0x206);
+        assert_eq!(
+            assemble_words(": sub v1 := 2 return : main v0 := 1 sub loop again"),
+            vec![0x1206, 0x6102, 0x00EE, 0x6001, 0x2202, 0x120A]
+        );
+
+        let mut machine = crate::machine::Machine::default();
+        machine.load_rom_from_bytes(&program.bytes).unwrap();
+        for _ in 0..6 {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.registers[..2], [1, 2]);
+    }
+
+    #[test]
+    fn test_conditions_and_loops() {
+        let source = "
+            : main
+            loop
+                if v0 == 5 then v1 += 1
+                if v0 != v2 then v1 += 1
+                if v3 key then v1 += 1
+                while v0 != 10
+                v0 += 1
+            again
+            i := long end
+            : end
+        ";
+        assert_eq!(
+            assemble_words(source),
+            vec![
+                0x4005, 0x7101, 0x5020, 0x7101, 0xE3A1, 0x7101, 0x400A, 0x1214, 0x7001, 0x1200,
+                0xF000, 0x0218,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            (
+                "v0 := 256",
+                1,
+                7,
+                "256 is out of range, expected -128 to 255",
+            ),
+            ("clear\n  vg := 1", 2, 3, "expected a register, found 'vg'"),
+            ("v0 := v1\njump nowhere", 2, 6, "undefined name 'nowhere'"),
+            ("  again", 1, 3, "'again' without 'loop'"),
+            ("loop\nclear", 1, 1, "'loop' without 'again'"),
+            ("sprite v0 v1", 1, 13, "unexpected end of file"),
+            ("v0 |= 3", 1, 7, "expected a register, found '3'"),
+            (": a\n: a", 2, 3, "'a' is already defined"),
+            ("i := 0x1000", 1, 6, "address 0x1000 doesn't fit in 12 bits"),
+            ("v0 := 1\n: sub return", 2, 13, "missing 'main' label"),
+        ];
+        for (source, line, column, message) in cases {
+            assert_eq!(
+                assemble(source),
+                Err(AssembleError {
+                    line,
+                    column,
+                    message: message.to_string()
+                }),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Assemble Octo source into a ROM, e.g. `chippy8-asm game.8o -o game.ch8`
+use chippy8::assembler::assemble;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: chippy8-asm <source.8o> [-o <rom.ch8>]";
+
+fn main() -> ExitCode {
+    let mut input = None;
+    let mut output = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next().map(PathBuf::from),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(PathBuf::from(arg)),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(input) = input else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let output = output.unwrap_or_else(|| input.with_extension("ch8"));
+
+    let source = match std::fs::read_to_string(&input) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{}: {}", input.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let program = match assemble(&source) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("{}:{}", input.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = std::fs::write(&output, &program.bytes) {
+        eprintln!("{}: {}", output.display(), err);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,60 @@
+//! Debug adapter for editors, speaking the Debug Adapter Protocol over stdio,
+//! or over a local socket with `--port N`. The ROM is given by the `program`
+//! attribute of the launch request, see `chippy8::dap`
+use chippy8::dap::DapServer;
+use std::io::{self, BufReader};
+use std::net::TcpListener;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: chippy8-dap [--port N]";
+
+fn main() -> ExitCode {
+    let mut port: Option<u16> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => match args.next().and_then(|port| port.parse().ok()) {
+                Some(value) => port = Some(value),
+                None => {
+                    eprintln!("{}", USAGE);
+                    return ExitCode::FAILURE;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let result = match port {
+        None => DapServer::new(io::stdout()).serve(BufReader::new(io::stdin())),
+        Some(port) => {
+            // Only accept local connections, the protocol has no authentication
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    eprintln!("failed to listen on port {}: {}", port, err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            eprintln!("listening on 127.0.0.1:{}", port);
+            listener.accept().and_then(|(stream, _)| {
+                stream.set_nodelay(true)?;
+                let reader = BufReader::new(stream.try_clone()?);
+                DapServer::new(stream).serve(reader)
+            })
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Print a listing of a ROM, e.g. `chippy8-disasm --cowgod roms/ibm_logo.ch8`,
+//! or its control-flow graph in Graphviz DOT format with `--dot`. Opcodes are
+//! decoded for the platform guessed from the file extension
+use chippy8::analysis::analyze;
+use chippy8::disassembler::{listing, Syntax};
+use chippy8::machine::Platform;
+use chippy8::rom::{Rom, ROM_START_ADDRESS};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: chippy8-disasm [--octo | --cowgod | --dot] <rom>";
+
+fn main() -> ExitCode {
+    let mut syntax = Syntax::Octo;
+    let mut dot = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--octo" => syntax = Syntax::Octo,
+            "--cowgod" => syntax = Syntax::Cowgod,
+            "--dot" => dot = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let platform = Platform::from_path(&path);
+    match Rom::from_file(&path) {
+        Ok(rom) if dot => {
+            print!(
+                "{}",
+                analyze(rom.bytes(), ROM_START_ADDRESS, platform).to_dot()
+            );
+            ExitCode::SUCCESS
+        }
+        Ok(rom) => {
+            print!(
+                "{}",
+                listing(rom.bytes(), ROM_START_ADDRESS, platform, syntax)
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            ExitCode::FAILURE
+        }
+    }
+}
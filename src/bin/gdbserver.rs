@@ -0,0 +1,64 @@
+//! Debug a ROM from GDB, e.g. `chippy8-gdbserver roms/ibm_logo.ch8` then
+//! `target remote localhost:1234` in GDB
+use chippy8::gdb::GdbServer;
+use chippy8::machine::{Machine, Platform};
+use chippy8::rom::Rom;
+use std::net::TcpListener;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: chippy8-gdbserver <rom> [--port N]";
+
+fn main() -> ExitCode {
+    let mut rom_path = None;
+    let mut port = 1234;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => match args.next().and_then(|port| port.parse().ok()) {
+                Some(value) => port = value,
+                None => {
+                    eprintln!("{}", USAGE);
+                    return ExitCode::FAILURE;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if rom_path.is_none() && !arg.starts_with('-') => rom_path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(rom_path) = rom_path else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    let mut machine = Machine::new(Platform::from_path(&rom_path));
+    if let Err(err) = Rom::from_file(&rom_path).and_then(|rom| machine.load_rom(rom)) {
+        eprintln!("{}: {}", rom_path, err);
+        return ExitCode::FAILURE;
+    }
+    // Only accept local connections, the protocol has no authentication
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("failed to listen on port {}: {}", port, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!("listening on 127.0.0.1:{}", port);
+    let result = listener
+        .accept()
+        .and_then(|(stream, _)| GdbServer::new(machine).serve(stream));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
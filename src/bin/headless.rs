@@ -0,0 +1,232 @@
+//! Run a ROM without any window and dump the screen and registers at the end,
+//! e.g. `chippy8-headless roms/ibm_logo.ch8 --frames 60 --screen ibm.png`
+use chippy8::dump::{display_to_ascii, display_to_pbm, registers_to_json, write_display_png};
+use chippy8::headless::{play, record, run, KeyScript, RunLength};
+use chippy8::machine::{Machine, Platform};
+use chippy8::movie::Movie;
+use chippy8::rom::Rom;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: chippy8-headless <rom> [options]
+
+options:
+  --frames N          run for N frames (default: 60)
+  --instructions N    run for N instructions instead of frames
+  --ipf N             instructions per frame (default: 11)
+  --platform P        chip8, schip or xochip (default: from the ROM extension)
+  --seed N            seed of the random number generator (default: 0)
+  --keys SCRIPT       key events like '10+A 20-A': press A at frame 10,
+                      release it at frame 20 (instructions with --instructions)
+  --screen PATH       write the screen to PATH: .png, .pbm, or ASCII for any
+                      other extension or '-' for stdout (default: '-')
+  --registers PATH    write the registers as JSON to PATH, or '-' for stdout
+  --record PATH       record the keypad of each frame in a movie file
+  --play PATH         replay a movie file instead of running, checking that it
+                      ends in the recorded state; the options above about
+                      running are then ignored";
+
+struct Options {
+    rom: String,
+    length: RunLength,
+    instructions_per_frame: u32,
+    platform: Option<Platform>,
+    seed: u64,
+    keys: KeyScript,
+    screen: String,
+    registers: Option<String>,
+    record: Option<String>,
+    play: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        length: RunLength::Frames(60),
+        instructions_per_frame: 11,
+        platform: None,
+        seed: 0,
+        keys: KeyScript::default(),
+        screen: "-".to_string(),
+        registers: None,
+        record: None,
+        play: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(String::new());
+        }
+        if !arg.starts_with("--") {
+            options.rom = arg;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid number for {}: {}", arg, value))
+        };
+        match arg.as_str() {
+            "--frames" => options.length = RunLength::Frames(number()?),
+            "--instructions" => options.length = RunLength::Instructions(number()?),
+            "--ipf" => {
+                options.instructions_per_frame = match number()? {
+                    n @ 1..=0xFFFF => n as u32,
+                    _ => return Err(format!("invalid number for {}: {}", arg, value)),
+                }
+            }
+            "--seed" => options.seed = number()?,
+            "--platform" => {
+                options.platform = Some(match value.as_str() {
+                    "chip8" => Platform::Chip8,
+                    "schip" => Platform::SuperChip,
+                    "xochip" => Platform::XoChip,
+                    _ => return Err(format!("unknown platform: {}", value)),
+                })
+            }
+            "--keys" => options.keys = KeyScript::parse(&value).map_err(|err| err.to_string())?,
+            "--screen" => options.screen = value,
+            "--registers" => options.registers = Some(value),
+            "--record" => options.record = Some(value),
+            "--play" => options.play = Some(value),
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    if options.rom.is_empty() {
+        return Err("missing ROM".to_string());
+    }
+    if options.record.is_some() && matches!(options.length, RunLength::Instructions(_)) {
+        return Err("movies are recorded frame by frame, use --frames".to_string());
+    }
+    if options.record.is_some() && options.play.is_some() {
+        return Err("can't both record and play a movie".to_string());
+    }
+    Ok(options)
+}
+
+/// Open `path` for writing, with '-' meaning stdout
+fn create_output(path: &str) -> io::Result<Box<dyn Write>> {
+    if path == "-" {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+fn write_outputs(machine: &mut Machine, options: &Options) -> io::Result<()> {
+    let mut screen = create_output(&options.screen)?;
+    if options.screen.ends_with(".png") {
+        write_display_png(&mut machine.display, &mut screen)?;
+    } else if options.screen.ends_with(".pbm") {
+        screen.write_all(display_to_pbm(&machine.display).as_bytes())?;
+    } else {
+        screen.write_all(display_to_ascii(&machine.display).as_bytes())?;
+    }
+    screen.flush()?;
+    if let Some(path) = &options.registers {
+        let mut registers = create_output(path)?;
+        registers.write_all(registers_to_json(machine).as_bytes())?;
+        registers.flush()?;
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("{}", err);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let rom = match Rom::from_file(&options.rom) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("{}: {}", options.rom, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(path) = &options.play {
+        return play_movie(rom, path, &options);
+    }
+    let platform = options
+        .platform
+        .unwrap_or_else(|| Platform::from_path(&options.rom));
+    let mut machine = Machine::with_seed(platform, options.seed);
+    if let Err(err) = machine.load_rom(rom) {
+        eprintln!("{}: {}", options.rom, err);
+        return ExitCode::FAILURE;
+    }
+
+    let result = match (&options.record, options.length) {
+        (Some(path), RunLength::Frames(frames)) => {
+            let mut movie = Movie::new(&machine, options.instructions_per_frame);
+            let result = record(&mut machine, frames, &options.keys, &mut movie);
+            if let Err(err) = std::fs::write(path, movie.to_json()) {
+                eprintln!("failed to write {}: {}", path, err);
+                return ExitCode::FAILURE;
+            }
+            result
+        }
+        _ => run(
+            &mut machine,
+            options.length,
+            options.instructions_per_frame,
+            &options.keys,
+        ),
+    };
+    // Dump the state even on errors, it's what's needed to understand them
+    if let Err(err) = write_outputs(&mut machine, &options) {
+        eprintln!("failed to write output: {}", err);
+        return ExitCode::FAILURE;
+    }
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn play_movie(rom: Rom, path: &str, options: &Options) -> ExitCode {
+    let movie = match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| Movie::from_json(&json).map_err(|err| err.to_string()))
+    {
+        Ok(movie) => movie,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut machine = match movie.create_machine(rom) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = play(&mut machine, &movie);
+    if let Err(err) = write_outputs(&mut machine, options) {
+        eprintln!("failed to write output: {}", err);
+        return ExitCode::FAILURE;
+    }
+    match result {
+        Ok(summary) => {
+            eprintln!("replayed {} frames, final state matches", summary.frames);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
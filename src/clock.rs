@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+/// A source of time, used to decrement the delay and sound timers at 60hz.
+/// Any `FnMut() -> Duration` closure can be used as one.
+pub trait TimeSource: Send {
+    /// Time elapsed since an arbitrary but fixed origin
+    fn elapsed(&mut self) -> Duration;
+}
+
+impl<F> TimeSource for F
+where
+    F: FnMut() -> Duration + Send,
+{
+    fn elapsed(&mut self) -> Duration {
+        self()
+    }
+}
+
+/// Wall-clock time, using a monotonic clock
+pub struct SystemTimeSource {
+    origin: Instant,
+}
+
+impl Default for SystemTimeSource {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl TimeSource for SystemTimeSource {
+    fn elapsed(&mut self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// What drives the decrement of the delay and sound timers
+pub enum TimerClock {
+    /// Decrement at 60hz of the given time source
+    Time(Box<dyn TimeSource>),
+    /// Decrement once every `instructions_per_tick` executed instructions
+    Cycles { instructions_per_tick: u32 },
+    /// Only decrement on explicit calls to `Timers::decrement`, typically once
+    /// per emulated frame
+    Frames,
+}
+
+impl TimerClock {
+    pub fn system() -> Self {
+        TimerClock::Time(Box::<SystemTimeSource>::default())
+    }
+}
+
+impl Default for TimerClock {
+    fn default() -> Self {
+        TimerClock::system()
+    }
+}
@@ -0,0 +1,820 @@
+//! A Debug Adapter Protocol server, to debug ROMs from editors like VS Code
+//! or Neovim. Messages are JSON preceded by a `Content-Length` header, over
+//! stdio or a socket.
+//! See https://microsoft.github.io/debug-adapter-protocol/specification
+//!
+//! The `launch` request takes the path of the ROM as `program`, which can also
+//! be Octo source that is then assembled. Function breakpoints are labels of
+//! that source, or of the Octo source given as `source`, or addresses.
+//! There is a single thread, with one stack frame per return address on the
+//! stack of the machine. It runs at 60 frames per second, without display or
+//! keypad.
+use crate::assembler::assemble;
+use crate::clock::TimerClock;
+use crate::debugger::{BreakpointKind, DebugStep, Debugger, Hit};
+use crate::instructions::Instruction;
+use crate::machine::{Machine, MachineError, Platform, StepOutcome};
+use crate::rom::Rom;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::sync::mpsc::{channel, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREAD_ID: u64 = 1;
+const FRAMES_PER_SECOND: u32 = 60;
+const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 11;
+/// `variablesReference` of the scopes
+const REGISTERS_REFERENCE: u64 = 1;
+const TIMERS_REFERENCE: u64 = 2;
+/// Requests are small, a longer `Content-Length` is most likely garbage
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+/// Read one message, or None at the end of the stream
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("message of {} bytes is too long", length),
+        ));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Where a resumed machine stops, besides breakpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Continue,
+    /// After a single instruction
+    Instruction,
+    /// Back at `pc` with `stack_pointer` return addresses, to step over calls
+    Return {
+        pc: usize,
+        stack_pointer: usize,
+    },
+    /// Once the stack has fewer than this many return addresses
+    StackBelow(usize),
+}
+
+/// Why a running machine stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Stop {
+    Breakpoint(Hit),
+    Step,
+    Exception(MachineError),
+    /// The program exited through 00FD
+    Exited,
+}
+
+/// The launched program
+struct Session {
+    machine: Machine,
+    /// Label of each address, from the Octo source if any
+    symbols: BTreeMap<usize, String>,
+    labels: BTreeMap<String, usize>,
+    instructions_per_frame: u32,
+    stop_on_entry: bool,
+}
+
+impl Session {
+    /// `label+0x2` for the closest label before `address`, or the address
+    fn symbolize(&self, address: usize) -> String {
+        match self.symbols.range(..=address).next_back() {
+            Some((&start, label)) if start == address => label.clone(),
+            Some((&start, label)) => format!("{}+{:#x}", label, address - start),
+            None => format!("{:#05x}", address),
+        }
+    }
+
+    /// A label, or an address in hexadecimal with a 0x prefix or in decimal
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.labels
+            .get(name)
+            .copied()
+            .or_else(|| parse_address(name))
+    }
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Assemble the Octo source at `path`, returning the ROM and its labels
+fn assemble_labels(path: &str) -> Result<(Vec<u8>, BTreeMap<String, usize>), String> {
+    let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let program = assemble(&source).map_err(|err| format!("{}:{}", path, err))?;
+    Ok((program.bytes, program.labels))
+}
+
+pub struct DapServer<W: Write> {
+    writer: W,
+    seq: u64,
+    /// Events to send after the response to the current request
+    events: Vec<Value>,
+    session: Option<Session>,
+    debugger: Debugger,
+    /// Debugger ids of the function and instruction breakpoints, which are
+    /// also their ids in the protocol
+    function_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+    /// Set while the machine runs
+    running: Option<Target>,
+    /// Whether the next instruction executes even if a breakpoint is on it,
+    /// so that resuming always makes progress
+    resuming: bool,
+    disconnected: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            seq: 0,
+            events: vec![],
+            session: None,
+            debugger: Debugger::new(),
+            function_breakpoints: vec![],
+            instruction_breakpoints: vec![],
+            running: None,
+            resuming: false,
+            disconnected: false,
+        }
+    }
+
+    /// Serve requests read from `reader` until the client disconnects, or
+    /// until a message can't be read
+    pub fn serve<R: BufRead + Send + 'static>(&mut self, reader: R) -> io::Result<()> {
+        // Requests like pause must be handled while the machine runs, so they
+        // are read on another thread
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut reader = reader;
+            // Stop at the end of the stream, or after passing on an error
+            while let Some(result) = read_message(&mut reader).transpose() {
+                let failed = result.is_err();
+                if tx.send(result).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let frame_duration = Duration::from_secs(1) / FRAMES_PER_SECOND;
+        let mut next_frame = Instant::now();
+        while !self.disconnected {
+            let message = if self.running.is_some() {
+                match rx.try_recv() {
+                    Ok(message) => Some(message?),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(message) => Some(message?),
+                    Err(_) => break,
+                }
+            };
+            if let Some(message) = message {
+                self.handle_message(&message)?;
+                continue;
+            }
+            if let Some(stop) = self.run_frame() {
+                self.stop(stop);
+                self.send_events()?;
+            }
+            // Sleep until the next frame, without trying to catch up if we're late
+            next_frame = (next_frame + frame_duration).max(Instant::now());
+            thread::sleep(next_frame - Instant::now());
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.writer, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let mut event = json!({"type": "event", "event": event});
+        if !body.is_null() {
+            event["body"] = body;
+        }
+        self.events.push(event);
+    }
+
+    fn send_events(&mut self) -> io::Result<()> {
+        for event in std::mem::take(&mut self.events) {
+            self.send(event)?;
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: &Value) -> io::Result<()> {
+        if message["type"] != "request" {
+            return Ok(());
+        }
+        let command = message["command"].as_str().unwrap_or_default();
+        let result = self.handle_request(command, &message["arguments"]);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(err) => response["message"] = json!(err),
+        }
+        self.send(response)?;
+        self.send_events()
+    }
+
+    fn handle_request(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => {
+                self.launch(arguments)?;
+                self.event("initialized", Value::Null);
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => {
+                let count = arguments["breakpoints"].as_array().map_or(0, Vec::len);
+                let unverified = json!({
+                    "verified": false,
+                    "message": "source lines aren't supported, use a function breakpoint on a label",
+                });
+                Ok(json!({"breakpoints": vec![unverified; count]}))
+            }
+            "setFunctionBreakpoints" => {
+                let breakpoints = self.set_breakpoints(arguments, false)?;
+                Ok(json!({"breakpoints": breakpoints}))
+            }
+            "setInstructionBreakpoints" => {
+                let breakpoints = self.set_breakpoints(arguments, true)?;
+                Ok(json!({"breakpoints": breakpoints}))
+            }
+            "configurationDone" => {
+                if self.session()?.stop_on_entry {
+                    self.event("stopped", stopped_body("entry", None));
+                } else {
+                    self.resume(Target::Continue)?;
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": "main"}]})),
+            "stackTrace" => self.stack_trace(arguments),
+            "scopes" => Ok(json!({"scopes": [
+                {"name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false},
+                {"name": "Timers", "variablesReference": TIMERS_REFERENCE, "expensive": false},
+            ]})),
+            "variables" => self.variables(arguments),
+            "continue" => {
+                self.resume(Target::Continue)?;
+                Ok(json!({"allThreadsContinued": true}))
+            }
+            "next" => {
+                let machine = &self.session()?.machine;
+                let target = match machine.decode_next_instruction() {
+                    Ok(Instruction::Subroutine(_)) => Target::Return {
+                        pc: machine.program_counter + 2,
+                        stack_pointer: machine.stack_pointer(),
+                    },
+                    _ => Target::Instruction,
+                };
+                self.resume(target).map(|_| Value::Null)
+            }
+            "stepIn" => self.resume(Target::Instruction).map(|_| Value::Null),
+            "stepOut" => {
+                let stack_pointer = self.session()?.machine.stack_pointer();
+                self.resume(Target::StackBelow(stack_pointer))
+                    .map(|_| Value::Null)
+            }
+            "pause" => {
+                if self.running.take().is_some() {
+                    self.event("stopped", stopped_body("pause", None));
+                }
+                Ok(Value::Null)
+            }
+            "terminate" | "disconnect" => {
+                self.running = None;
+                self.disconnected = command == "disconnect";
+                if command == "terminate" {
+                    self.event("terminated", Value::Null);
+                }
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+
+    fn session(&self) -> Result<&Session, String> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| "no program launched".to_string())
+    }
+
+    /// Load the ROM in a new machine, which waits for `configurationDone`
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("missing 'program', the path of the ROM or Octo source")?;
+        let platform = match arguments["platform"].as_str() {
+            None => Platform::from_path(program),
+            Some("chip8") => Platform::Chip8,
+            Some("schip") => Platform::SuperChip,
+            Some("xochip") => Platform::XoChip,
+            Some(platform) => return Err(format!("unknown platform: {}", platform)),
+        };
+        let (rom, mut labels) = if program.ends_with(".8o") {
+            let (bytes, labels) = assemble_labels(program)?;
+            (Rom::from_bytes(bytes), labels)
+        } else {
+            (Rom::from_file(program), BTreeMap::new())
+        };
+        if let Some(source) = arguments["source"].as_str() {
+            labels = assemble_labels(source)?.1;
+        }
+        let instructions_per_frame = match arguments["instructionsPerFrame"].as_u64() {
+            None => DEFAULT_INSTRUCTIONS_PER_FRAME,
+            Some(n @ 1..=0xFFFF) => n as u32,
+            Some(n) => return Err(format!("invalid instructionsPerFrame: {}", n)),
+        };
+
+        let mut machine = match arguments["seed"].as_u64() {
+            Some(seed) => Machine::with_seed(platform, seed),
+            None => Machine::new(platform),
+        };
+        machine.timers.set_clock(TimerClock::Frames);
+        rom.and_then(|rom| machine.load_rom(rom))
+            .map_err(|err| format!("{}: {}", program, err))?;
+        let mut symbols = BTreeMap::new();
+        for (label, &address) in &labels {
+            symbols.entry(address).or_insert_with(|| label.clone());
+        }
+        self.session = Some(Session {
+            machine,
+            symbols,
+            labels,
+            instructions_per_frame,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+        self.debugger = Debugger::new();
+        self.function_breakpoints.clear();
+        self.instruction_breakpoints.clear();
+        self.running = None;
+        Ok(())
+    }
+
+    /// Replace the function or instruction breakpoints
+    fn set_breakpoints(
+        &mut self,
+        arguments: &Value,
+        instruction: bool,
+    ) -> Result<Vec<Value>, String> {
+        let Some(session) = &self.session else {
+            return Err("no program launched".to_string());
+        };
+        let ids = if instruction {
+            &mut self.instruction_breakpoints
+        } else {
+            &mut self.function_breakpoints
+        };
+        for id in ids.drain(..) {
+            self.debugger.remove(id);
+        }
+        let requested = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        Ok(requested
+            .iter()
+            .map(|breakpoint| {
+                let address = if instruction {
+                    let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+                    let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+                    parse_address(reference)
+                        .and_then(|address| address.checked_add_signed(offset as isize))
+                } else {
+                    session.resolve(breakpoint["name"].as_str().unwrap_or(""))
+                };
+                match address {
+                    Some(address) => {
+                        let id = self.debugger.add(BreakpointKind::Pc(address));
+                        ids.push(id);
+                        json!({
+                            "id": id,
+                            "verified": true,
+                            "instructionReference": format!("{:#05x}", address),
+                        })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "message": "not a label or an address",
+                    }),
+                }
+            })
+            .collect())
+    }
+
+    fn resume(&mut self, target: Target) -> Result<(), String> {
+        if self.session()?.machine.halted {
+            return Err("the program exited".to_string());
+        }
+        self.running = Some(target);
+        self.resuming = true;
+        Ok(())
+    }
+
+    /// Run one frame of the machine, until it reaches a breakpoint or the
+    /// target of a step
+    fn run_frame(&mut self) -> Option<Stop> {
+        let target = self.running?;
+        let session = self.session.as_mut()?;
+        let machine = &mut session.machine;
+        for _ in 0..session.instructions_per_frame {
+            if machine.halted {
+                return Some(Stop::Exited);
+            }
+            let mut step = self.debugger.step(machine);
+            if self.resuming && matches!(step, Ok(DebugStep { outcome: None, .. })) {
+                step = self.debugger.step(machine);
+            }
+            self.resuming = false;
+            let step = match step {
+                Ok(step) => step,
+                Err(err) => return Some(Stop::Exception(err)),
+            };
+            if let Some(hit) = step.hit {
+                return Some(Stop::Breakpoint(hit));
+            }
+            let reached = match target {
+                Target::Continue => false,
+                Target::Instruction => true,
+                Target::Return { pc, stack_pointer } => {
+                    machine.program_counter == pc && machine.stack_pointer() == stack_pointer
+                }
+                Target::StackBelow(stack_pointer) => machine.stack_pointer() < stack_pointer,
+            };
+            if reached {
+                return Some(Stop::Step);
+            }
+            if step.outcome != Some(StepOutcome::Executed) {
+                break;
+            }
+        }
+        machine.timers.decrement();
+        None
+    }
+
+    fn stop(&mut self, stop: Stop) {
+        self.running = None;
+        match stop {
+            Stop::Breakpoint(hit) => {
+                let mut body = stopped_body("breakpoint", Some(hit.to_string()));
+                body["hitBreakpointIds"] = json!([hit.breakpoint]);
+                self.event("stopped", body);
+            }
+            Stop::Step => self.event("stopped", stopped_body("step", None)),
+            Stop::Exception(err) => {
+                let mut body = stopped_body("exception", Some(err.to_string()));
+                body["text"] = json!(err.to_string());
+                self.event("stopped", body);
+            }
+            Stop::Exited => {
+                self.event("exited", json!({"exitCode": 0}));
+                self.event("terminated", Value::Null);
+            }
+        }
+    }
+
+    /// The current instruction, then the call of each return address on the
+    /// stack, innermost first
+    fn stack_trace(&self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let machine = &session.machine;
+        let calls = machine.stack[..machine.stack_pointer()]
+            .iter()
+            .rev()
+            .map(|&return_address| (return_address as usize).saturating_sub(2));
+        let frames: Vec<Value> = std::iter::once(machine.program_counter)
+            .chain(calls)
+            .enumerate()
+            .map(|(id, address)| {
+                json!({
+                    "id": id,
+                    "name": session.symbolize(address),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#05x}", address),
+                })
+            })
+            .collect();
+        let total = frames.len();
+        let start = arguments["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match arguments["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => total,
+        };
+        let frames: Vec<Value> = frames.into_iter().skip(start).take(levels).collect();
+        Ok(json!({"stackFrames": frames, "totalFrames": total}))
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let machine = &session.machine;
+        let byte = |value: u8| format!("{:#04x} ({})", value, value);
+        let variables: Vec<(String, String)> = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => {
+                let mut registers: Vec<(String, String)> = machine
+                    .registers
+                    .iter()
+                    .enumerate()
+                    .map(|(x, &value)| (format!("V{:X}", x), byte(value)))
+                    .collect();
+                registers.push(("I".to_string(), format!("{:#05x}", machine.index_register)));
+                registers.push((
+                    "PC".to_string(),
+                    format!(
+                        "{:#05x} ({})",
+                        machine.program_counter,
+                        session.symbolize(machine.program_counter)
+                    ),
+                ));
+                registers.push(("SP".to_string(), machine.stack_pointer().to_string()));
+                registers
+            }
+            Some(TIMERS_REFERENCE) => vec![
+                ("DT".to_string(), byte(machine.timers.delay)),
+                ("ST".to_string(), byte(machine.timers.sound)),
+            ],
+            _ => return Err("unknown variables reference".to_string()),
+        };
+        let variables: Vec<Value> = variables
+            .into_iter()
+            .map(|(name, value)| json!({"name": name, "value": value, "variablesReference": 0}))
+            .collect();
+        Ok(json!({"variables": variables}))
+    }
+}
+
+fn stopped_body(reason: &str, description: Option<String>) -> Value {
+    let mut body = json!({
+        "reason": reason,
+        "threadId": THREAD_ID,
+        "allThreadsStopped": true,
+    });
+    if let Some(description) = description {
+        body["description"] = json!(description);
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread::JoinHandle;
+
+    /// A scripted editor, talking to a server on another thread
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        seq: u64,
+        server: JoinHandle<io::Result<()>>,
+    }
+
+    impl Client {
+        fn connect() -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                stream.set_nodelay(true).unwrap();
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                DapServer::new(stream).serve(reader)
+            });
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+                seq: 0,
+                server,
+            }
+        }
+
+        /// Send a request and return its response, skipping events
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut self.writer, &request).unwrap();
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["type"] == "response" {
+                    assert_eq!(message["request_seq"], self.seq);
+                    return message;
+                }
+            }
+        }
+
+        /// Body of the successful response to a request
+        fn body(&mut self, command: &str, arguments: Value) -> Value {
+            let response = self.request(command, arguments);
+            assert_eq!(response["success"], true, "{}", response);
+            response["body"].clone()
+        }
+
+        fn wait_event(&mut self, event: &str) -> Value {
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["type"] == "event" && message["event"] == event {
+                    return message["body"].clone();
+                }
+            }
+        }
+
+        fn variable(&mut self, reference: u64, name: &str) -> String {
+            let body = self.body("variables", json!({"variablesReference": reference}));
+            let variables = body["variables"].as_array().unwrap();
+            let variable = variables.iter().find(|v| v["name"] == name).unwrap();
+            variable["value"].as_str().unwrap().to_string()
+        }
+
+        fn frame_names(&mut self) -> Vec<String> {
+            let body = self.body("stackTrace", json!({"threadId": THREAD_ID}));
+            body["stackFrames"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|frame| frame["name"].as_str().unwrap().to_string())
+                .collect()
+        }
+
+        fn disconnect(mut self) {
+            self.body("disconnect", json!({}));
+            self.server.join().unwrap().unwrap();
+        }
+    }
+
+    fn write_temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("chippy8-dap-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    const PROGRAM: &str = "
+        : main
+            v0 := 3
+        : loop
+            add_one
+            if v0 != 10 then jump loop
+        : done
+            exit
+        : add_one
+            v0 += 1
+            return
+        ";
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let path = write_temp_file("program.8o", PROGRAM.as_bytes());
+        let labels = assemble(PROGRAM).unwrap().labels;
+        let mut client = Client::connect();
+        let capabilities = client.body("initialize", json!({"adapterID": "chippy8"}));
+        assert_eq!(capabilities["supportsFunctionBreakpoints"], true);
+        client.body(
+            "launch",
+            json!({"program": path, "platform": "schip", "stopOnEntry": true}),
+        );
+        client.wait_event("initialized");
+        let breakpoints = client.body(
+            "setFunctionBreakpoints",
+            json!({"breakpoints": [{"name": "add_one"}, {"name": "nowhere"}]}),
+        );
+        assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+        assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+        let id = breakpoints["breakpoints"][0]["id"].clone();
+        client.body("configurationDone", json!({}));
+        assert_eq!(client.wait_event("stopped")["reason"], "entry");
+
+        client.body("continue", json!({"threadId": THREAD_ID}));
+        let stopped = client.wait_event("stopped");
+        assert_eq!(stopped["reason"], "breakpoint");
+        assert_eq!(stopped["hitBreakpointIds"], json!([id]));
+        assert_eq!(client.frame_names(), ["add_one", "loop"]);
+        assert_eq!(client.variable(REGISTERS_REFERENCE, "V0"), "0x03 (3)");
+        assert_eq!(client.variable(REGISTERS_REFERENCE, "SP"), "1");
+        assert_eq!(client.variable(TIMERS_REFERENCE, "DT"), "0x00 (0)");
+
+        client.body("stepIn", json!({"threadId": THREAD_ID}));
+        assert_eq!(client.wait_event("stopped")["reason"], "step");
+        assert_eq!(client.frame_names(), ["add_one+0x2", "loop"]);
+        client.body("stepOut", json!({"threadId": THREAD_ID}));
+        client.wait_event("stopped");
+        assert_eq!(client.frame_names(), ["loop+0x2"]);
+
+        // Step over the call, with a breakpoint on the loop instead
+        client.body("setFunctionBreakpoints", json!({"breakpoints": []}));
+        let reference = format!("{:#x}", labels["loop"]);
+        client.body(
+            "setInstructionBreakpoints",
+            json!({"breakpoints": [{"instructionReference": reference}]}),
+        );
+        client.body("continue", json!({"threadId": THREAD_ID}));
+        assert_eq!(client.wait_event("stopped")["reason"], "breakpoint");
+        client.body("next", json!({"threadId": THREAD_ID}));
+        assert_eq!(client.wait_event("stopped")["reason"], "step");
+        assert_eq!(client.frame_names(), ["loop+0x2"]);
+        assert_eq!(client.variable(REGISTERS_REFERENCE, "V0"), "0x05 (5)");
+
+        client.body("setInstructionBreakpoints", json!({"breakpoints": []}));
+        client.body("continue", json!({"threadId": THREAD_ID}));
+        assert_eq!(client.wait_event("exited")["exitCode"], 0);
+        client.wait_event("terminated");
+        assert_eq!(client.variable(REGISTERS_REFERENCE, "V0"), "0x0a (10)");
+        let response = client.request("continue", json!({"threadId": THREAD_ID}));
+        assert_eq!(response["message"], "the program exited");
+        client.disconnect();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        // Messages that can't be read stop the server with an error
+        let huge = format!("Content-Length: {}\r\n\r\n", usize::MAX);
+        for message in [huge.as_str(), "Content-Length: 5\r\n\r\n{bad}"] {
+            let mut client = Client::connect();
+            client.body("initialize", json!({}));
+            client.writer.write_all(message.as_bytes()).unwrap();
+            let err = client.server.join().unwrap().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        // An unknown opcode after a jump
+        let path = write_temp_file("error.ch8", &[0x12, 0x04, 0x00, 0x00, 0x51, 0x21]);
+        let mut client = Client::connect();
+        client.body("initialize", json!({}));
+        assert_eq!(client.request("threads", json!({}))["success"], true);
+        assert_eq!(client.request("continue", json!({}))["success"], false);
+        assert_eq!(client.request("launch", json!({}))["success"], false);
+        assert_eq!(client.request("evaluate", json!({}))["success"], false);
+
+        client.body("launch", json!({"program": path}));
+        client.body(
+            "setFunctionBreakpoints",
+            json!({"breakpoints": [{"name": "0x202"}]}),
+        );
+        client.body("configurationDone", json!({}));
+        let stopped = client.wait_event("stopped");
+        assert_eq!(stopped["reason"], "exception");
+        assert!(stopped["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("unknown opcode 0x5121"));
+        assert_eq!(client.frame_names(), ["0x204"]);
+        client.disconnect();
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,630 @@
+//! Breakpoints and watchpoints, checked while executing instructions.
+//!
+//! Breakpoints on the program counter and on opcodes stop before the
+//! instruction executes, while watchpoints on memory and registers stop right
+//! after the instruction that triggered them.
+use crate::instructions::{decode, Instruction};
+use crate::machine::{Machine, MachineError, MemoryAccess, Platform, StepOutcome};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// A register that can be watched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    /// The index register
+    I,
+}
+
+impl Register {
+    fn value(&self, registers: &[u8; 16], index_register: u16) -> u16 {
+        match self {
+            Register::V(x) => registers[*x as usize] as u16,
+            Register::I => index_register,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+        }
+    }
+}
+
+/// A set of opcodes to break on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeClass {
+    /// Opcodes matching a pattern like `DXYN` or `FX55`, in uppercase, where
+    /// X, Y and N nibbles match anything
+    Pattern([char; 4]),
+    /// Any opcode that doesn't decode to an instruction
+    Unknown,
+}
+
+impl OpcodeClass {
+    pub fn matches(&self, opcode: u16, platform: Platform) -> bool {
+        match self {
+            OpcodeClass::Pattern(pattern) => {
+                pattern
+                    .iter()
+                    .enumerate()
+                    .all(|(i, c)| match c.to_digit(16) {
+                        Some(nibble) => (opcode >> (12 - 4 * i)) & 0xF == nibble as u16,
+                        None => true,
+                    })
+            }
+            OpcodeClass::Unknown => matches!(decode(opcode, platform), Instruction::Unknown(_)),
+        }
+    }
+}
+
+impl fmt::Display for OpcodeClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpcodeClass::Pattern(pattern) => write!(f, "{}", pattern.iter().collect::<String>()),
+            OpcodeClass::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// What happens to the watched memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    /// A write leaves one of the watched bytes equal to the value
+    ValueEquals(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Stop before executing the instruction at this address
+    Pc(usize),
+    Memory {
+        range: Range<usize>,
+        access: WatchAccess,
+    },
+    /// Stop when the register changes, or only when it changes to `value`
+    Register {
+        register: Register,
+        value: Option<u16>,
+    },
+    /// Stop before executing an opcode of this class
+    Opcode(OpcodeClass),
+}
+
+/// `pc ADDR`, `read RANGE`, `write RANGE`, `equals RANGE VALUE`,
+/// `reg REGISTER [VALUE]` or `opcode PATTERN`, where a range is `ADDR` or
+/// `START..END`, as parsed by `BreakpointKind::from_str`
+impl fmt::Display for BreakpointKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let range = |range: &Range<usize>| {
+            if range.len() == 1 {
+                format!("{:#05x}", range.start)
+            } else {
+                format!("{:#05x}..{:#05x}", range.start, range.end)
+            }
+        };
+        match self {
+            BreakpointKind::Pc(address) => write!(f, "pc {:#05x}", address),
+            BreakpointKind::Memory {
+                range: r,
+                access: WatchAccess::Read,
+            } => write!(f, "read {}", range(r)),
+            BreakpointKind::Memory {
+                range: r,
+                access: WatchAccess::Write,
+            } => write!(f, "write {}", range(r)),
+            BreakpointKind::Memory {
+                range: r,
+                access: WatchAccess::ValueEquals(value),
+            } => write!(f, "equals {} {:#04x}", range(r), value),
+            BreakpointKind::Register { register, value } => match value {
+                Some(value) => write!(f, "reg {} {:#x}", register, value),
+                None => write!(f, "reg {}", register),
+            },
+            BreakpointKind::Opcode(class) => write!(f, "opcode {}", class),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBreakpointError {
+    pub input: String,
+    pub message: String,
+}
+
+impl fmt::Display for ParseBreakpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid breakpoint '{}': {}", self.input, self.message)
+    }
+}
+
+impl std::error::Error for ParseBreakpointError {}
+
+/// A number in hexadecimal with a 0x prefix, or in decimal
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// A non-empty range of addresses, within the largest RAM of any platform
+fn parse_range(text: &str) -> Option<Range<usize>> {
+    let (start, end) = match text.split_once("..") {
+        Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+        None => {
+            let address = parse_number(text)?;
+            (address, address.checked_add(1)?)
+        }
+    };
+    (start < end && end <= Platform::XoChip.ram_size()).then_some(start..end)
+}
+
+fn parse_register(text: &str) -> Option<Register> {
+    if text.eq_ignore_ascii_case("i") {
+        return Some(Register::I);
+    }
+    let x = text.strip_prefix(['v', 'V'])?;
+    if x.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(x, 16).ok().map(Register::V)
+}
+
+fn parse_opcode_class(text: &str) -> Option<OpcodeClass> {
+    if text.eq_ignore_ascii_case("unknown") {
+        return Some(OpcodeClass::Unknown);
+    }
+    let pattern: Vec<char> = text.chars().map(|c| c.to_ascii_uppercase()).collect();
+    if !pattern
+        .iter()
+        .all(|c| c.is_ascii_hexdigit() || matches!(c, 'X' | 'Y' | 'N'))
+    {
+        return None;
+    }
+    pattern.try_into().ok().map(OpcodeClass::Pattern)
+}
+
+impl FromStr for BreakpointKind {
+    type Err = ParseBreakpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |message: &str| ParseBreakpointError {
+            input: s.to_string(),
+            message: message.to_string(),
+        };
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let range = |i: usize| {
+            words
+                .get(i)
+                .and_then(|w| parse_range(w))
+                .ok_or_else(|| err("expected an address or a START..END range"))
+        };
+        let memory = |range, access| BreakpointKind::Memory { range, access };
+        let kind = match words.first().copied() {
+            Some("pc") => BreakpointKind::Pc(
+                words
+                    .get(1)
+                    .and_then(|w| parse_number(w))
+                    .ok_or_else(|| err("expected an address"))?,
+            ),
+            Some("read") => memory(range(1)?, WatchAccess::Read),
+            Some("write") => memory(range(1)?, WatchAccess::Write),
+            Some("equals") => {
+                let value = words
+                    .get(2)
+                    .and_then(|w| parse_number(w))
+                    .and_then(|v| u8::try_from(v).ok())
+                    .ok_or_else(|| err("expected a byte value"))?;
+                memory(range(1)?, WatchAccess::ValueEquals(value))
+            }
+            Some("reg") => {
+                let register = words
+                    .get(1)
+                    .and_then(|w| parse_register(w))
+                    .ok_or_else(|| err("expected V0 to VF or I"))?;
+                let value = match words.get(2) {
+                    Some(w) => Some(
+                        parse_number(w)
+                            .and_then(|v| u16::try_from(v).ok())
+                            .ok_or_else(|| err("expected a value"))?,
+                    ),
+                    None => None,
+                };
+                BreakpointKind::Register { register, value }
+            }
+            Some("opcode") => BreakpointKind::Opcode(
+                words
+                    .get(1)
+                    .and_then(|w| parse_opcode_class(w))
+                    .ok_or_else(|| err("expected a pattern like DXYN, or 'unknown'"))?,
+            ),
+            _ => return Err(err("expected pc, read, write, equals, reg or opcode")),
+        };
+        let expected_words = match &kind {
+            BreakpointKind::Memory {
+                access: WatchAccess::ValueEquals(_),
+                ..
+            } => 3,
+            BreakpointKind::Register { value: Some(_), .. } => 3,
+            _ => 2,
+        };
+        if words.len() != expected_words {
+            return Err(err("unexpected trailing words"));
+        }
+        Ok(kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub enabled: bool,
+    pub kind: BreakpointKind,
+}
+
+/// Why the execution stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    /// Id of the breakpoint that triggered
+    pub breakpoint: usize,
+    /// Address of the instruction that is about to execute for breakpoints,
+    /// or that just executed for watchpoints
+    pub pc: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:#05x}", self.reason, self.pc)
+    }
+}
+
+/// Result of `Debugger::step`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugStep {
+    /// None if a breakpoint stopped before executing the instruction
+    pub outcome: Option<StepOutcome>,
+    pub hit: Option<Hit>,
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    /// Where a breakpoint stopped before executing, so that resuming from
+    /// there doesn't stop again right away
+    stopped_at: Option<usize>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an enabled breakpoint, returning its id
+    pub fn add(&mut self, kind: BreakpointKind) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            enabled: true,
+            kind,
+        });
+        id
+    }
+
+    /// Returns false if there is no such breakpoint
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != len
+    }
+
+    /// Returns false if there is no such breakpoint
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.breakpoints.iter_mut().find(|b| b.id == id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter().filter(|b| b.enabled)
+    }
+
+    /// Breakpoints triggering before the instruction at the program counter
+    fn check_before(&self, machine: &Machine) -> Option<Hit> {
+        let pc = machine.program_counter;
+        let opcode = machine
+            .ram
+            .get(pc..pc + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+        self.enabled().find_map(|breakpoint| {
+            let reason = match &breakpoint.kind {
+                BreakpointKind::Pc(address) if *address == pc => "breakpoint".to_string(),
+                BreakpointKind::Opcode(class) => match opcode {
+                    Some(opcode) if class.matches(opcode, machine.platform) => {
+                        format!("opcode {} ({:04X})", class, opcode)
+                    }
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            Some(Hit {
+                breakpoint: breakpoint.id,
+                pc,
+                reason,
+            })
+        })
+    }
+
+    /// Watchpoints triggered by the instruction at `pc` that just executed,
+    /// given the registers before it
+    fn check_after(
+        &self,
+        machine: &Machine,
+        pc: usize,
+        registers: &[u8; 16],
+        index_register: u16,
+    ) -> Option<Hit> {
+        let access = machine.last_memory_access();
+        self.enabled().find_map(|breakpoint| {
+            let reason = match &breakpoint.kind {
+                BreakpointKind::Memory {
+                    range,
+                    access: watch,
+                } => {
+                    let (accessed, write) = match access {
+                        Some(MemoryAccess::Read(accessed)) => (accessed, false),
+                        Some(MemoryAccess::Write(accessed)) => (accessed, true),
+                        None => return None,
+                    };
+                    let overlap = accessed.start.max(range.start)..accessed.end.min(range.end);
+                    if overlap.is_empty() {
+                        return None;
+                    }
+                    match watch {
+                        WatchAccess::Read if !write => {
+                            format!("read of {:#05x}", overlap.start)
+                        }
+                        WatchAccess::Write if write => {
+                            format!("write to {:#05x}", overlap.start)
+                        }
+                        WatchAccess::ValueEquals(value) if write => {
+                            let address = overlap
+                                .clone()
+                                .find(|&address| machine.ram[address] == *value)?;
+                            format!("{:#05x} set to {:#04x}", address, value)
+                        }
+                        _ => return None,
+                    }
+                }
+                BreakpointKind::Register { register, value } => {
+                    let before = register.value(registers, index_register);
+                    let after = register.value(&machine.registers, machine.index_register);
+                    if before == after || value.is_some_and(|value| value != after) {
+                        return None;
+                    }
+                    format!("{} changed from {:#x} to {:#x}", register, before, after)
+                }
+                _ => return None,
+            };
+            Some(Hit {
+                breakpoint: breakpoint.id,
+                pc,
+                reason,
+            })
+        })
+    }
+
+    /// Execute one instruction, unless a breakpoint stops before it
+    pub fn step(&mut self, machine: &mut Machine) -> Result<DebugStep, MachineError> {
+        let pc = machine.program_counter;
+        if self.stopped_at.take() != Some(pc) && !machine.halted {
+            if let Some(hit) = self.check_before(machine) {
+                self.stopped_at = Some(pc);
+                return Ok(DebugStep {
+                    outcome: None,
+                    hit: Some(hit),
+                });
+            }
+        }
+        let (registers, index_register) = (machine.registers, machine.index_register);
+        let outcome = machine.execute_one()?;
+        if outcome == StepOutcome::WaitingForKey {
+            // Don't stop again on every attempt while waiting
+            self.stopped_at = Some(pc);
+        }
+        Ok(DebugStep {
+            outcome: Some(outcome),
+            hit: self.check_after(machine, pc, &registers, index_register),
+        })
+    }
+
+    /// Same as `Machine::run_frame`, stopping on breakpoints. When one is hit
+    /// the frame is cut short and the timers are not decremented
+    pub fn run_frame(
+        &mut self,
+        machine: &mut Machine,
+        instructions_per_frame: u32,
+    ) -> Result<Option<Hit>, MachineError> {
+        let mut executed = 0;
+        while executed < instructions_per_frame && !machine.halted {
+            let step = self.step(machine)?;
+            if step.hit.is_some() {
+                return Ok(step.hit);
+            }
+            executed += 1;
+            if step.outcome != Some(StepOutcome::Executed) {
+                break;
+            }
+        }
+        machine.timers.decrement();
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::COUNTING_LOOP as PROGRAM;
+
+    /// Step until a breakpoint hits, at most `max` instructions
+    fn run_until_hit(debugger: &mut Debugger, machine: &mut Machine, max: usize) -> Option<Hit> {
+        (0..max).find_map(|_| debugger.step(machine).unwrap().hit)
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        for text in [
+            "pc 0x204",
+            "read 0x300",
+            "write 0x300..0x310",
+            "read 0xffff",
+            "equals 0x300..0x302 0x05",
+            "reg VA",
+            "reg I 0x300",
+            "opcode DXYN",
+            "opcode FX55",
+            "opcode 8XY4",
+            "opcode 00E0",
+            "opcode BNNN",
+            "opcode 5XY0",
+            "opcode unknown",
+        ] {
+            let kind: BreakpointKind = text.parse().unwrap();
+            assert_eq!(kind.to_string(), text);
+        }
+        assert_eq!(
+            "write 768".parse::<BreakpointKind>(),
+            Ok(BreakpointKind::Memory {
+                range: 0x300..0x301,
+                access: WatchAccess::Write
+            })
+        );
+        for invalid in [
+            "",
+            "pc",
+            "pc 0x20g",
+            "read 0x310..0x300",
+            "read 18446744073709551615",
+            "write 0x10000",
+            "write 0xfff0..0x10001",
+            "equals 0x300 256",
+            "reg V10",
+            "opcode DXY",
+            "pc 0x200 0x202",
+        ] {
+            assert!(invalid.parse::<BreakpointKind>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_pc_breakpoint() {
+        let mut machine = Machine::from_octo(Platform::SuperChip, PROGRAM);
+        let mut debugger = Debugger::new();
+        let looping = assemble(PROGRAM).unwrap().labels["loop"];
+        let id = debugger.add(BreakpointKind::Pc(looping));
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert_eq!(
+            hit,
+            Hit {
+                breakpoint: id,
+                pc: looping,
+                reason: "breakpoint".to_string()
+            }
+        );
+        assert_eq!(machine.registers[0], 3);
+        // Resuming executes the instruction, then stops on the next loop
+        let step = debugger.step(&mut machine).unwrap();
+        assert_eq!(step.outcome, Some(StepOutcome::Executed));
+        assert_eq!(step.hit, None);
+        assert!(run_until_hit(&mut debugger, &mut machine, 10).is_some());
+        assert_eq!(machine.registers[0], 4);
+
+        assert!(debugger.set_enabled(id, false));
+        assert_eq!(run_until_hit(&mut debugger, &mut machine, 10), None);
+        assert!(debugger.remove(id));
+        assert!(!debugger.remove(id));
+    }
+
+    #[test]
+    fn test_memory_watchpoints() {
+        let buffer = assemble(PROGRAM).unwrap().labels["buffer"];
+        let mut machine = Machine::from_octo(Platform::SuperChip, PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.add(
+            format!("equals {:#x}..{:#x} 7", buffer, buffer + 4)
+                .parse()
+                .unwrap(),
+        );
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert_eq!(hit.reason, format!("{:#05x} set to 0x07", buffer));
+        assert_eq!(machine.registers[0], 7);
+
+        let mut machine = Machine::from_octo(Platform::SuperChip, PROGRAM);
+        let mut debugger = Debugger::new();
+        // The sprite is a single byte, the next ones are never read
+        debugger.add(
+            format!("read {:#x}..{:#x}", buffer + 1, buffer + 4)
+                .parse()
+                .unwrap(),
+        );
+        debugger.add(format!("write {:#x}", buffer).parse().unwrap());
+        debugger.add(format!("read {:#x}", buffer).parse().unwrap());
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert_eq!(hit.reason, format!("write to {:#05x}", buffer));
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert_eq!(hit.reason, format!("read of {:#05x}", buffer));
+        assert_eq!(hit.breakpoint, 2);
+        assert_eq!(machine.registers[0], 4);
+    }
+
+    #[test]
+    fn test_register_watchpoints() {
+        let mut machine = Machine::from_octo(Platform::SuperChip, PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.add("reg I".parse().unwrap());
+        debugger.add("reg V0 6".parse().unwrap());
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert!(hit.reason.starts_with("I changed from 0x0 to"), "{}", hit);
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert_eq!(hit.reason, "V0 changed from 0x5 to 0x6");
+        assert_eq!(hit.pc, 0x204);
+    }
+
+    #[test]
+    fn test_opcode_breakpoints() {
+        let mut machine = Machine::from_octo(Platform::SuperChip, PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.add("opcode DXYN".parse().unwrap());
+        let hit = run_until_hit(&mut debugger, &mut machine, 100).unwrap();
+        assert_eq!(hit.reason, "opcode DXYN (D001)");
+
+        let mut machine = Machine::new(Platform::Chip8);
+        machine.load_rom_from_instrhex(&[0x6001, 0x5121]).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.add("opcode unknown".parse().unwrap());
+        // Stops before the machine raises an error
+        assert_eq!(
+            debugger.run_frame(&mut machine, 10).unwrap().unwrap().pc,
+            0x202
+        );
+        assert!(debugger.run_frame(&mut machine, 10).is_err());
+    }
+}
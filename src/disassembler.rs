@@ -0,0 +1,312 @@
+use crate::instructions::{decode, Instruction};
+use crate::machine::Platform;
+use std::fmt::Write;
+
+/// The assembly dialect to render instructions in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Mnemonics from Cowgod's technical reference, e.g. `LD VC, #DE`.
+    /// See http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#3.1
+    Cowgod,
+    /// Octo's assembly language, e.g. `vc := 0xDE`.
+    /// See https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+    Octo,
+}
+
+/// One entry of a listing: an instruction, or a single byte of data when a
+/// ROM has an odd size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>,
+}
+
+impl Line {
+    /// The mnemonic for this line, without address or bytes
+    pub fn text(&self, syntax: Syntax) -> String {
+        match self.instruction {
+            Some(Instruction::LoadLongIndex) if self.bytes.len() == 4 => {
+                let nnnn = ((self.bytes[2] as u16) << 8) | self.bytes[3] as u16;
+                match syntax {
+                    Syntax::Cowgod => format!("LD I, #{:04X}", nnnn),
+                    Syntax::Octo => format!("i := long 0x{:04X}", nnnn),
+                }
+            }
+            Some(instruction) => format_instruction(instruction, syntax),
+            None => format_byte(self.bytes[0], syntax),
+        }
+    }
+
+    /// The line as shown in a listing, e.g. `0x200  60 01        v0 := 0x01`
+    pub fn to_listing_string(&self, syntax: Syntax) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            "0x{:03X}  {:<11}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text(syntax)
+        )
+    }
+}
+
+fn format_byte(byte: u8, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Cowgod => format!("DB #{:02X}", byte),
+        Syntax::Octo => format!("0x{:02X}", byte),
+    }
+}
+
+/// Render a single instruction. `LoadLongIndex` is rendered without its
+/// address since it lives outside of the opcode, see `Line::text` for that
+pub fn format_instruction(instruction: Instruction, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Cowgod => format_cowgod(instruction),
+        Syntax::Octo => format_octo(instruction),
+    }
+}
+
+fn format_cowgod(instruction: Instruction) -> String {
+    use Instruction::*;
+    match instruction {
+        Zero => "SYS #000".to_string(),
+        ClearScreen => "CLS".to_string(),
+        Return => "RET".to_string(),
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollUp(n) => format!("SCU {}", n),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        LowRes => "LOW".to_string(),
+        HighRes => "HIGH".to_string(),
+        Jump(nnn) => format!("JP #{:03X}", nnn),
+        Subroutine(nnn) => format!("CALL #{:03X}", nnn),
+        SkipIfEqualRegVal(x, nn) => format!("SE V{:X}, #{:02X}", x, nn),
+        SkipIfNotEqualRegVal(x, nn) => format!("SNE V{:X}, #{:02X}", x, nn),
+        SkipIfEqualRegReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+        LoadRange(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+        SetRegToVal(x, nn) => format!("LD V{:X}, #{:02X}", x, nn),
+        AddValToReg(x, nn) => format!("ADD V{:X}, #{:02X}", x, nn),
+        Set(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        SubtractXY(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        SubtractYX(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SkipIfNotEqualRegReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        SetIndexRegister(nnn) => format!("LD I, #{:03X}", nnn),
+        JumpWithOffset(nnn) => format!("JP V0, #{:03X}", nnn),
+        Random(x, nn) => format!("RND V{:X}, #{:02X}", x, nn),
+        Display(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        SkipIfKeyPressed(x) => format!("SKP V{:X}", x),
+        SkipIfKeyNotPressed(x) => format!("SKNP V{:X}", x),
+        LoadLongIndex => "LD I, long".to_string(),
+        SelectPlanes(n) => format!("PLANE {}", n),
+        ReadDelayTimer(x) => format!("LD V{:X}, DT", x),
+        GetKey(x) => format!("LD V{:X}, K", x),
+        SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+        SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+        AddToIndex(x) => format!("ADD I, V{:X}", x),
+        FontCharacter(x) => format!("LD F, V{:X}", x),
+        BigFontCharacter(x) => format!("LD HF, V{:X}", x),
+        ConvertToDecimal(x) => format!("LD B, V{:X}", x),
+        RegistersToMemory(x) => format!("LD [I], V{:X}", x),
+        MemoryToRegisters(x) => format!("LD V{:X}, [I]", x),
+        SaveFlags(x) => format!("LD R, V{:X}", x),
+        LoadFlags(x) => format!("LD V{:X}, R", x),
+        Unknown(opcode) => format!("DW #{:04X}", opcode),
+    }
+}
+
+fn format_octo(instruction: Instruction) -> String {
+    use Instruction::*;
+    match instruction {
+        ClearScreen => "clear".to_string(),
+        Return => "return".to_string(),
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        LowRes => "lores".to_string(),
+        HighRes => "hires".to_string(),
+        Jump(nnn) => format!("jump 0x{:03X}", nnn),
+        Subroutine(nnn) => format!(":call 0x{:03X}", nnn),
+        // Octo conditionals tell when the next instruction is executed, which
+        // is the opposite of when it's skipped
+        SkipIfEqualRegVal(x, nn) => format!("if v{:x} != 0x{:02X} then", x, nn),
+        SkipIfNotEqualRegVal(x, nn) => format!("if v{:x} == 0x{:02X} then", x, nn),
+        SkipIfEqualRegReg(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        SetRegToVal(x, nn) => format!("v{:x} := 0x{:02X}", x, nn),
+        AddValToReg(x, nn) => format!("v{:x} += 0x{:02X}", x, nn),
+        Set(x, y) => format!("v{:x} := v{:x}", x, y),
+        Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Add(x, y) => format!("v{:x} += v{:x}", x, y),
+        SubtractXY(x, y) => format!("v{:x} -= v{:x}", x, y),
+        ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        SubtractYX(x, y) => format!("v{:x} =- v{:x}", x, y),
+        ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        SkipIfNotEqualRegReg(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        SetIndexRegister(nnn) => format!("i := 0x{:03X}", nnn),
+        JumpWithOffset(nnn) => format!("jump0 0x{:03X}", nnn),
+        Random(x, nn) => format!("v{:x} := random 0x{:02X}", x, nn),
+        Display(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        SkipIfKeyPressed(x) => format!("if v{:x} -key then", x),
+        SkipIfKeyNotPressed(x) => format!("if v{:x} key then", x),
+        LoadLongIndex => "i := long".to_string(),
+        SelectPlanes(n) => format!("plane {}", n),
+        ReadDelayTimer(x) => format!("v{:x} := delay", x),
+        GetKey(x) => format!("v{:x} := key", x),
+        SetDelayTimer(x) => format!("delay := v{:x}", x),
+        SetSoundTimer(x) => format!("buzzer := v{:x}", x),
+        AddToIndex(x) => format!("i += v{:x}", x),
+        FontCharacter(x) => format!("i := hex v{:x}", x),
+        BigFontCharacter(x) => format!("i := bighex v{:x}", x),
+        ConvertToDecimal(x) => format!("bcd v{:x}", x),
+        RegistersToMemory(x) => format!("save v{:x}", x),
+        MemoryToRegisters(x) => format!("load v{:x}", x),
+        SaveFlags(x) => format!("saveflags v{:x}", x),
+        LoadFlags(x) => format!("loadflags v{:x}", x),
+        // Octo has no mnemonic for those, so emit them as raw bytes
+        Zero | Unknown(_) => {
+            let opcode = instruction.encode();
+            format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF)
+        }
+    }
+}
+
+/// Decode the instruction at `address` in `memory`, which is 4 bytes long
+/// for F000 NNNN on XO-CHIP and 2 bytes otherwise. Returns None past the end
+/// of memory
+pub fn decode_at(memory: &[u8], address: usize, platform: Platform) -> Option<Line> {
+    match memory.get(address..address + 2) {
+        Some(&[hi, lo]) => {
+            let instruction = decode(((hi as u16) << 8) | lo as u16, platform);
+            let len = match instruction {
+                Instruction::LoadLongIndex if address + 4 <= memory.len() => 4,
+                _ => 2,
+            };
+            Some(Line {
+                address,
+                bytes: memory[address..address + len].to_vec(),
+                instruction: Some(instruction),
+            })
+        }
+        _ => memory.get(address).map(|&byte| Line {
+            address,
+            bytes: vec![byte],
+            instruction: None,
+        }),
+    }
+}
+
+/// Decode a whole program, one instruction after the other starting at its
+/// first byte, which is loaded at `start_address`.
+/// Like any linear disassembler, this also decodes sprites and other data as
+/// if they were instructions
+pub fn disassemble(program: &[u8], start_address: usize, platform: Platform) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    while let Some(mut line) = decode_at(program, offset, platform) {
+        offset += line.bytes.len();
+        line.address += start_address;
+        lines.push(line);
+    }
+    lines
+}
+
+/// A listing of a whole program with addresses, raw bytes and mnemonics,
+/// one instruction per line
+pub fn listing(program: &[u8], start_address: usize, platform: Platform, syntax: Syntax) -> String {
+    let mut out = String::new();
+    for line in disassemble(program, start_address, platform) {
+        writeln!(out, "{}", line.to_listing_string(syntax)).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_instruction() {
+        let instruction = Instruction::SetRegToVal(12, 0xDE);
+        assert_eq!(
+            format_instruction(instruction, Syntax::Cowgod),
+            "LD VC, #DE"
+        );
+        assert_eq!(format_instruction(instruction, Syntax::Octo), "vc := 0xDE");
+
+        let instruction = Instruction::Display(1, 2, 5);
+        assert_eq!(
+            format_instruction(instruction, Syntax::Cowgod),
+            "DRW V1, V2, 5"
+        );
+        assert_eq!(
+            format_instruction(instruction, Syntax::Octo),
+            "sprite v1 v2 5"
+        );
+
+        let instruction = Instruction::SkipIfEqualRegVal(3, 0x10);
+        assert_eq!(
+            format_instruction(instruction, Syntax::Cowgod),
+            "SE V3, #10"
+        );
+        assert_eq!(
+            format_instruction(instruction, Syntax::Octo),
+            "if v3 != 0x10 then"
+        );
+
+        let instruction = Instruction::Unknown(0x0123);
+        assert_eq!(format_instruction(instruction, Syntax::Cowgod), "DW #0123");
+        assert_eq!(format_instruction(instruction, Syntax::Octo), "0x01 0x23");
+    }
+
+    #[test]
+    fn test_listing() {
+        let program = [0x60, 0x01, 0xF0, 0x00, 0x12, 0x34, 0x12, 0x00, 0xAB];
+        assert_eq!(
+            listing(&program, 0x200, Platform::XoChip, Syntax::Octo),
+            "0x200  60 01        v0 := 0x01\n\
+             0x202  F0 00 12 34  i := long 0x1234\n\
+             0x206  12 00        jump 0x200\n\
+             0x208  AB           0xAB\n"
+        );
+        assert_eq!(
+            listing(&program, 0x200, Platform::XoChip, Syntax::Cowgod),
+            "0x200  60 01        LD V0, #01\n\
+             0x202  F0 00 12 34  LD I, #1234\n\
+             0x206  12 00        JP #200\n\
+             0x208  AB           DB #AB\n"
+        );
+        // F000 is a single unknown opcode before XO-CHIP
+        assert_eq!(
+            listing(&program[2..6], 0x202, Platform::SuperChip, Syntax::Octo),
+            "0x202  F0 00        0xF0 0x00\n\
+             0x204  12 34        jump 0x234\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_at_truncated_long_index() {
+        let line = decode_at(&[0xF0, 0x00, 0x12], 0, Platform::XoChip).unwrap();
+        assert_eq!(line.bytes, vec![0xF0, 0x00]);
+        assert_eq!(line.text(Syntax::Octo), "i := long");
+        assert_eq!(
+            decode_at(&[0xF0, 0x00, 0x12], 2, Platform::XoChip)
+                .unwrap()
+                .bytes,
+            vec![0x12]
+        );
+        assert_eq!(decode_at(&[0xF0, 0x00, 0x12], 3, Platform::XoChip), None);
+    }
+}
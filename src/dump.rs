@@ -0,0 +1,119 @@
+//! Text and image exports of the machine state, for scripts and tests
+use crate::machine::{Display, Machine};
+use std::fmt::Write as _;
+use std::io;
+
+/// Character used for each palette index in ASCII dumps
+const ASCII_PIXELS: [char; 4] = ['.', '#', 'o', '@'];
+
+/// The display as text, one line per row, with `.` for unlit pixels and `#`
+/// for lit ones. With XO-CHIP planes, `o` is plane 2 only and `@` both planes
+pub fn display_to_ascii(display: &Display) -> String {
+    let mut out = String::with_capacity((display.width() + 1) * display.height());
+    for y in 0..display.height() {
+        for x in 0..display.width() {
+            out.push(ASCII_PIXELS[display.color_index(x, y)]);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The display as a plain PBM (P1) image, where any lit plane is black
+pub fn display_to_pbm(display: &Display) -> String {
+    let mut out = format!("P1\n{} {}\n", display.width(), display.height());
+    for y in 0..display.height() {
+        let row: Vec<&str> = (0..display.width())
+            .map(|x| {
+                if display.color_index(x, y) != 0 {
+                    "1"
+                } else {
+                    "0"
+                }
+            })
+            .collect();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+/// Write the display as a PNG, with the display palette colors
+pub fn write_display_png<W: io::Write>(display: &mut Display, writer: W) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(writer, display.width() as u32, display.height() as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer
+        .write_image_data(display.rgba())
+        .map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+/// The registers, stack and timers as a JSON object
+pub fn registers_to_json(machine: &Machine) -> String {
+    let join = |values: &mut dyn Iterator<Item = String>| values.collect::<Vec<_>>().join(", ");
+    let mut out = String::new();
+    writeln!(out, "{{").unwrap();
+    writeln!(out, "  \"pc\": {},", machine.program_counter).unwrap();
+    writeln!(out, "  \"i\": {},", machine.index_register).unwrap();
+    writeln!(
+        out,
+        "  \"v\": [{}],",
+        join(&mut machine.registers.iter().map(|v| v.to_string()))
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "  \"stack\": [{}],",
+        join(
+            &mut machine.stack[..machine.stack_pointer()]
+                .iter()
+                .map(|v| v.to_string())
+        )
+    )
+    .unwrap();
+    writeln!(out, "  \"delay\": {},", machine.timers.delay).unwrap();
+    writeln!(out, "  \"sound\": {},", machine.timers.sound).unwrap();
+    writeln!(out, "  \"hires\": {},", machine.display.is_hires()).unwrap();
+    writeln!(out, "  \"waiting_for_key\": {},", machine.waiting_for_key).unwrap();
+    writeln!(out, "  \"halted\": {}", machine.halted).unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Platform;
+    use crate::quirks::SpriteEdges;
+
+    #[test]
+    fn test_display_dumps() {
+        let mut display = Display::default();
+        display.draw_sprite(0, 1, 0, &[0b101], 3, SpriteEdges::Clip);
+        display.draw_sprite(1, 2, 0, &[0b11], 2, SpriteEdges::Clip);
+
+        let ascii = display_to_ascii(&display);
+        assert_eq!(ascii.lines().count(), 32);
+        assert!(ascii.starts_with(".#o@....."));
+
+        let pbm = display_to_pbm(&display);
+        assert!(pbm.starts_with("P1\n64 32\n0 1 1 1 0 0"));
+
+        let mut png = vec![];
+        write_display_png(&mut display, &mut png).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+    }
+
+    #[test]
+    fn test_registers_to_json() {
+        let mut machine = Machine::new(Platform::Chip8);
+        machine.registers[1] = 7;
+        machine.timers.delay = 3;
+        let json = registers_to_json(&machine);
+        assert!(json.contains("\"v\": [0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],"));
+        assert!(json.contains("\"stack\": [],"));
+        assert!(json.contains("\"delay\": 3,"));
+    }
+}
@@ -0,0 +1,700 @@
+//! A server for the GDB remote serial protocol, to debug ROMs from GDB or any
+//! other front-end speaking it, e.g. with `target remote localhost:1234`.
+//!
+//! Registers are numbered V0 to VF, I, PC, SP, DT and ST. I and PC are two
+//! big-endian bytes, the others one byte. Memory is the RAM of the machine.
+//! Software and hardware breakpoints, and read and write watchpoints, are
+//! handled by a `Debugger`.
+//! See https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+use crate::debugger::{BreakpointKind, Debugger, Hit, WatchAccess};
+use crate::machine::{Machine, MachineError};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+/// V0 to VF, I, PC, SP, DT and ST
+pub const REGISTER_COUNT: usize = 21;
+
+const REGISTER_NAMES: [&str; REGISTER_COUNT] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9", "va", "vb", "vc", "vd", "ve", "vf",
+    "i", "pc", "sp", "dt", "st",
+];
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// How often a running machine checks for an interrupt from the client
+const INTERRUPT_CHECK_INSTRUCTIONS: u64 = 1024;
+/// Largest packet data accepted, as advertised to the client
+const MAX_PACKET_SIZE: usize = 0x1000;
+
+/// Why the machine stopped after a step or continue packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Signal(u8),
+    /// A watchpoint of a Z packet type triggered on `address`
+    Watch {
+        kind: char,
+        address: usize,
+    },
+    /// The program exited through 00FD
+    Exited,
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Watch { kind, address } => {
+                let name = if *kind == '3' { "rwatch" } else { "watch" };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+            }
+            Stop::Exited => "W00".to_string(),
+        }
+    }
+}
+
+/// What to do after handling a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Response {
+    Reply(String),
+    /// Reply, then close the connection
+    ReplyAndClose(String),
+    Close,
+}
+
+pub struct GdbServer {
+    pub machine: Machine,
+    debugger: Debugger,
+    /// Debugger breakpoint ids of the Z packets, by type and address
+    breakpoints: HashMap<(char, usize), usize>,
+}
+
+impl GdbServer {
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            debugger: Debugger::new(),
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    /// Serve a client until it detaches, kills the program or disconnects
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut connection = Connection::new(stream);
+        while let Some(packet) = connection.read_packet()? {
+            let no_ack = packet == "QStartNoAckMode";
+            let response = self.handle_packet(&packet, &mut || connection.interrupted());
+            if connection.closed {
+                break;
+            }
+            match response {
+                Response::Reply(reply) => connection.write_packet(&reply)?,
+                Response::ReplyAndClose(reply) => {
+                    connection.write_packet(&reply)?;
+                    break;
+                }
+                Response::Close => break,
+            }
+            if no_ack {
+                connection.ack = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Response {
+        let error = || Response::Reply("E01".to_string());
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => Stop::Signal(SIGTRAP).reply(),
+            "g" => (0..REGISTER_COUNT)
+                .flat_map(|n| self.register_bytes(n))
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            "G" => match decode_hex(args) {
+                Some(bytes) if self.set_registers(&bytes) => "OK".to_string(),
+                _ => return error(),
+            },
+            "p" => match parse_hex(args).filter(|&n| n < REGISTER_COUNT) {
+                Some(n) => encode_hex(&self.register_bytes(n)),
+                None => return error(),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(n, value)| {
+                    Some((
+                        parse_hex(n).filter(|&n| n < REGISTER_COUNT)?,
+                        decode_hex(value)?,
+                    ))
+                });
+                match register {
+                    Some((n, value)) if self.set_register(n, &value) => "OK".to_string(),
+                    _ => return error(),
+                }
+            }
+            "m" => match parse_address_length(args)
+                .and_then(|(address, len)| self.machine.ram.get(address..address.checked_add(len)?))
+            {
+                Some(bytes) => encode_hex(bytes),
+                None => return error(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (address, len) = parse_address_length(range)?;
+                    decode_hex(data)
+                        .filter(|bytes| bytes.len() == len)
+                        .map(|bytes| (address, bytes))
+                });
+                match write {
+                    Some((address, bytes)) if self.write_memory(address, &bytes) => {
+                        "OK".to_string()
+                    }
+                    _ => return error(),
+                }
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(address) => self.machine.program_counter = address,
+                        None => return error(),
+                    }
+                }
+                self.resume(command == "s", interrupted).reply()
+            }
+            "Z" | "z" => return self.handle_breakpoint(command == "Z", args),
+            "H" => "OK".to_string(),
+            "k" => return Response::Close,
+            "D" => return Response::ReplyAndClose("OK".to_string()),
+            _ => self.handle_query(packet).unwrap_or_default(),
+        };
+        Response::Reply(reply)
+    }
+
+    /// Replies to the general query packets that are supported
+    fn handle_query(&self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some(format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                MAX_PACKET_SIZE
+            ));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = parse_address_length(range)?;
+            let xml = target_xml();
+            let chunk = xml.get(offset.min(xml.len())..)?;
+            let chunk = &chunk[..len.min(chunk.len())];
+            let more = offset + chunk.len() < xml.len();
+            return Some(format!("{}{}", if more { "m" } else { "l" }, chunk));
+        }
+        let reply = match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => return None,
+        };
+        Some(reply.to_string())
+    }
+
+    /// Z and z packets, `TYPE,ADDRESS,KIND` where the kind of a watchpoint is
+    /// its length
+    fn handle_breakpoint(&mut self, insert: bool, args: &str) -> Response {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(address), Some(len)) = (
+            fields.next().and_then(|kind| kind.chars().next()),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) else {
+            return Response::Reply("E01".to_string());
+        };
+        let breakpoint = match kind {
+            '0' | '1' => BreakpointKind::Pc(address),
+            '2' | '3' => match address
+                .checked_add(len)
+                .filter(|&end| len > 0 && end <= self.machine.ram.len())
+            {
+                Some(end) => BreakpointKind::Memory {
+                    range: address..end,
+                    access: if kind == '2' {
+                        WatchAccess::Write
+                    } else {
+                        WatchAccess::Read
+                    },
+                },
+                None => return Response::Reply("E01".to_string()),
+            },
+            // Access watchpoints aren't supported
+            _ => return Response::Reply(String::new()),
+        };
+        if insert {
+            if !self.breakpoints.contains_key(&(kind, address)) {
+                let id = self.debugger.add(breakpoint);
+                self.breakpoints.insert((kind, address), id);
+            }
+        } else if let Some(id) = self.breakpoints.remove(&(kind, address)) {
+            self.debugger.remove(id);
+        }
+        Response::Reply("OK".to_string())
+    }
+
+    /// Execute instructions until a breakpoint, an error, an interrupt or the
+    /// end of the program, or a single instruction if `step`
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> Stop {
+        let mut executed: u64 = 0;
+        loop {
+            if self.machine.halted {
+                return Stop::Exited;
+            }
+            let result = match self.debugger.step(&mut self.machine) {
+                // Resuming from a breakpoint executes its instruction
+                Ok(result) if executed == 0 && result.outcome.is_none() => {
+                    self.debugger.step(&mut self.machine)
+                }
+                result => result,
+            };
+            match result {
+                Ok(result) => {
+                    if let Some(hit) = result.hit {
+                        return self.hit_to_stop(&hit);
+                    }
+                }
+                Err(MachineError::UnknownOpcode { .. }) => return Stop::Signal(SIGILL),
+                Err(_) => return Stop::Signal(SIGSEGV),
+            }
+            executed += 1;
+            if step {
+                return Stop::Signal(SIGTRAP);
+            }
+            if executed.is_multiple_of(INTERRUPT_CHECK_INSTRUCTIONS) && interrupted() {
+                return Stop::Signal(SIGINT);
+            }
+        }
+    }
+
+    fn hit_to_stop(&self, hit: &Hit) -> Stop {
+        let key = self
+            .breakpoints
+            .iter()
+            .find_map(|(key, &id)| (id == hit.breakpoint).then_some(*key));
+        match key {
+            Some((kind @ ('2' | '3'), address)) => Stop::Watch { kind, address },
+            _ => Stop::Signal(SIGTRAP),
+        }
+    }
+
+    fn register_bytes(&self, n: usize) -> Vec<u8> {
+        let machine = &self.machine;
+        match n {
+            0..=15 => vec![machine.registers[n]],
+            16 => machine.index_register.to_be_bytes().to_vec(),
+            17 => (machine.program_counter as u16).to_be_bytes().to_vec(),
+            18 => vec![machine.stack_pointer() as u8],
+            19 => vec![machine.timers.delay],
+            20 => vec![machine.timers.sound],
+            _ => vec![],
+        }
+    }
+
+    /// Returns false if `value` doesn't have the size of the register
+    fn set_register(&mut self, n: usize, value: &[u8]) -> bool {
+        let machine = &mut self.machine;
+        match (n, value) {
+            (0..=15, &[value]) => machine.registers[n] = value,
+            (16, &[high, low]) => machine.index_register = u16::from_be_bytes([high, low]),
+            (17, &[high, low]) => {
+                machine.program_counter = u16::from_be_bytes([high, low]) as usize
+            }
+            (18, &[value]) => return machine.set_stack_pointer(value as usize).is_ok(),
+            (19, &[value]) => machine.timers.delay = value,
+            (20, &[value]) => machine.timers.sound = value,
+            _ => return false,
+        }
+        true
+    }
+
+    fn set_registers(&mut self, mut bytes: &[u8]) -> bool {
+        for n in 0..REGISTER_COUNT {
+            let size = self.register_bytes(n).len();
+            if bytes.len() < size || !self.set_register(n, &bytes[..size]) {
+                return false;
+            }
+            bytes = &bytes[size..];
+        }
+        bytes.is_empty()
+    }
+
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+        let ram_len = self.machine.ram.len();
+        if address
+            .checked_add(bytes.len())
+            .is_none_or(|end| end > ram_len)
+        {
+            return false;
+        }
+        // Also invalidates the decoded instructions
+        (address..)
+            .zip(bytes)
+            .all(|(address, &byte)| self.machine.poke(address, byte).is_ok())
+    }
+}
+
+/// Description of the registers, for clients that don't know about CHIP-8
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<feature name=\"org.chippy8.chip8\">\n",
+    );
+    for (n, name) in REGISTER_NAMES.iter().enumerate() {
+        let (bits, kind) = match *name {
+            "i" => (16, "data_ptr"),
+            "pc" => (16, "code_ptr"),
+            _ => (8, "uint8"),
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n",
+            name, bits, kind, n
+        );
+    }
+    xml + "</feature>\n</target>\n"
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// `ADDRESS,LENGTH` in hexadecimal
+fn parse_address_length(text: &str) -> Option<(usize, usize)> {
+    let (address, len) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Packet framing, `$DATA#CHECKSUM`, with acknowledgments unless the client
+/// asked for the no-ack mode
+struct Connection {
+    stream: TcpStream,
+    /// Bytes received and not handled yet
+    buffer: Vec<u8>,
+    ack: bool,
+    /// The client disconnected while the machine was running
+    closed: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        // Packets are small and each waits for a reply, don't delay them
+        let _ = stream.set_nodelay(true);
+        Self {
+            stream,
+            buffer: vec![],
+            ack: true,
+            closed: false,
+        }
+    }
+
+    /// Read more bytes into the buffer, returns false on end of stream
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut bytes = [0; 1024];
+        let len = self.stream.read(&mut bytes)?;
+        self.buffer.extend_from_slice(&bytes[..len]);
+        Ok(len > 0)
+    }
+
+    /// The next packet, or None once the client disconnected. Acks and
+    /// interrupts outside of a running machine are skipped
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(start) = self.buffer.iter().position(|&b| b == b'$') {
+                self.buffer.drain(..start);
+                if let Some(end) = self.buffer.iter().position(|&b| b == b'#') {
+                    if self.buffer.len() >= end + 3 {
+                        let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+                        let data = &packet[1..end];
+                        let expected = std::str::from_utf8(&packet[end + 1..])
+                            .ok()
+                            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+                        if self.ack && expected != Some(checksum(data)) {
+                            self.stream.write_all(b"-")?;
+                            continue;
+                        }
+                        if self.ack {
+                            self.stream.write_all(b"+")?;
+                        }
+                        return Ok(Some(unescape(data)));
+                    }
+                } else if self.buffer.len() > 1 + MAX_PACKET_SIZE {
+                    // Larger than advertised, drop what was received so far
+                    self.buffer.clear();
+                    if self.ack {
+                        self.stream.write_all(b"-")?;
+                    }
+                    continue;
+                }
+            } else {
+                self.buffer.clear();
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let data = escape(data);
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", checksum(&data)).as_bytes());
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.ack {
+                return Ok(());
+            }
+            if self.buffer.is_empty() && !self.fill()? {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+            match self.buffer.first() {
+                Some(b'-') => {
+                    self.buffer.remove(0);
+                }
+                Some(b'+') => {
+                    self.buffer.remove(0);
+                    return Ok(());
+                }
+                // Be lenient with clients that don't ack
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Whether the client sent an interrupt (0x03) or disconnected, without
+    /// blocking
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_ok() {
+            // Errors are handled by the next blocking read
+            if let Ok(false) = self.fill() {
+                self.closed = true;
+            }
+            let _ = self.stream.set_nonblocking(false);
+        }
+        if self.closed {
+            return true;
+        }
+        match self.buffer.iter().position(|&b| b == 0x03) {
+            Some(i) => {
+                self.buffer.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Bytes of a packet, with `#`, `$`, `}` and `*` escaped as `}` followed by
+/// the byte xor 0x20
+fn escape(data: &str) -> Vec<u8> {
+    let mut escaped = vec![];
+    for &byte in data.as_bytes() {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> String {
+    let mut unescaped = vec![];
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|b| b ^ 0x20)),
+            _ => unescaped.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::{Platform, COUNTING_LOOP as PROGRAM};
+    use std::net::{Shutdown, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    /// Plays GDB's side of the protocol against a `GdbServer` thread
+    struct Client {
+        stream: TcpStream,
+        server: JoinHandle<GdbServer>,
+    }
+
+    impl Client {
+        fn connect(machine: Machine) -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut server = GdbServer::new(machine);
+                server.serve(stream).unwrap();
+                server
+            });
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            Client { stream, server }
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        /// Send a packet and return the reply
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            assert_eq!(self.read_byte(), b'+', "no ack for {}", data);
+            self.read_reply()
+        }
+
+        fn read_reply(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+            let mut data = vec![];
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.read_byte(), self.read_byte()];
+            let sum = u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap();
+            assert_eq!(sum, checksum(&data));
+            self.stream.write_all(b"+").unwrap();
+            unescape(&data)
+        }
+
+        fn detach(mut self) -> GdbServer {
+            assert_eq!(self.send("D"), "OK");
+            self.server.join().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut client = Client::connect(Machine::from_octo(Platform::SuperChip, PROGRAM));
+        assert!(client.send("qSupported:swbreak+").contains("PacketSize"));
+        assert_eq!(client.send("?"), "S05");
+        let registers = client.send("g");
+        assert_eq!(registers.len(), 2 * 23);
+        // PC and SP
+        assert_eq!(&registers[36..42], "020000");
+        assert_eq!(client.send("m200,2"), "6003");
+        assert_eq!(client.send("M300,2:abcd"), "OK");
+        assert_eq!(client.send("m300,3"), "abcd00");
+        assert_eq!(client.send("mfff,2"), "E01");
+        assert_eq!(client.send("P3=2a"), "OK");
+        assert_eq!(client.send("p3"), "2a");
+        assert_eq!(client.send("P10=0123"), "OK");
+        assert_eq!(client.send("p10"), "0123");
+        assert_eq!(client.send("P10=01"), "E01");
+        assert_eq!(client.send("p15"), "E01");
+        assert_eq!(client.send("vMustReplyEmpty"), "");
+        let xml = client.send("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml") && xml.contains("name=\"pc\""));
+        assert!(client
+            .send("qXfer:features:read:target.xml:0,10")
+            .starts_with('m'));
+
+        let server = client.detach();
+        assert_eq!(server.machine.registers[3], 0x2a);
+        assert_eq!(server.machine.index_register, 0x0123);
+        assert_eq!(server.machine.ram[0x300..0x302], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let labels = assemble(PROGRAM).unwrap().labels;
+        let (looping, buffer) = (labels["loop"], labels["buffer"]);
+        let mut client = Client::connect(Machine::from_octo(Platform::SuperChip, PROGRAM));
+        assert_eq!(client.send(&format!("Z0,{:x},2", looping)), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p11"), format!("{:04x}", looping));
+        assert_eq!(client.send("p0"), "03");
+        // Continuing from the breakpoint executes it, then the next loop stops
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "04");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p0"), "04");
+        assert_eq!(client.send(&format!("z0,{:x},2", looping)), "OK");
+
+        assert_eq!(client.send(&format!("Z2,{:x},1", buffer)), "OK");
+        assert_eq!(client.send("c"), format!("T05watch:{:x};", buffer));
+        assert_eq!(client.send(&format!("m{:x},1", buffer)), "05");
+        assert_eq!(client.send(&format!("z2,{:x},1", buffer)), "OK");
+        assert_eq!(client.send("Z4,300,1"), "");
+        assert_eq!(client.send("c"), "W00");
+        let server = client.detach();
+        assert_eq!(server.machine.registers[0], 10);
+    }
+
+    #[test]
+    fn test_interrupt_and_errors() {
+        let mut client =
+            Client::connect(Machine::from_octo(Platform::SuperChip, ": main jump main"));
+        client.stream.write_all(b"$c#63").unwrap();
+        assert_eq!(client.read_byte(), b'+');
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.read_reply(), "S02");
+
+        // Bad checksums are nacked
+        client.stream.write_all(b"$?#00").unwrap();
+        assert_eq!(client.read_byte(), b'-');
+        assert_eq!(client.send("M200,2:5121"), "OK");
+        assert_eq!(client.send("s"), "S04");
+        assert_eq!(client.send("p11"), "0200");
+        assert_eq!(client.send("c1000"), "S0b");
+        assert_eq!(client.send("Mffffffffffffffff,1:00"), "E01");
+        assert_eq!(client.send("Z2,ffffffffffffffff,2"), "E01");
+        assert_eq!(client.send("Z2,fff,2"), "E01");
+        // A packet that never ends is dropped once past the advertised size
+        let mut packet = vec![b'$'];
+        packet.resize(2 + MAX_PACKET_SIZE, b'0');
+        client.stream.write_all(&packet).unwrap();
+        assert_eq!(client.read_byte(), b'-');
+        assert_eq!(client.send("?"), "S05");
+        // Not a known command, even though it doesn't start with ASCII
+        client.stream.write_all(b"$\xff#ff").unwrap();
+        assert_eq!(client.read_byte(), b'+');
+        assert_eq!(client.read_reply(), "");
+        client.detach();
+
+        // Disconnecting while running stops the server
+        let mut client =
+            Client::connect(Machine::from_octo(Platform::SuperChip, ": main jump main"));
+        client.stream.write_all(b"$c#63").unwrap();
+        assert_eq!(client.read_byte(), b'+');
+        client.stream.shutdown(Shutdown::Both).unwrap();
+        client.server.join().unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Running a machine without any window, e.g. from scripts or tests
+use crate::clock::TimerClock;
+use crate::machine::{Machine, MachineError, StepOutcome};
+use crate::movie::{Movie, MovieError};
+use std::fmt;
+
+/// How long to run for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunLength {
+    Instructions(u64),
+    /// 60hz frames, each running a fixed number of instructions
+    Frames(u64),
+}
+
+/// A key pressed or released at a given time, counted in the unit of the
+/// `RunLength`: instructions or frames since the start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub time: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseKeyScriptError {
+    pub event: String,
+}
+
+impl fmt::Display for ParseKeyScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid key event '{}', expected TIME+KEY or TIME-KEY, e.g. 10+A",
+            self.event
+        )
+    }
+}
+
+impl std::error::Error for ParseKeyScriptError {}
+
+/// Scripted keypad input, sorted by time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn new(mut events: Vec<KeyEvent>) -> Self {
+        events.sort_by_key(|event| event.time);
+        Self { events }
+    }
+
+    /// Parse comma or whitespace separated events, where `10+A` presses key A
+    /// at time 10 and `20-A` releases it at time 20
+    pub fn parse(script: &str) -> Result<Self, ParseKeyScriptError> {
+        let mut events = vec![];
+        for event in script.split(|c: char| c == ',' || c.is_whitespace()) {
+            if event.is_empty() {
+                continue;
+            }
+            let err = || ParseKeyScriptError {
+                event: event.to_string(),
+            };
+            let split = event.find(['+', '-']).ok_or_else(err)?;
+            let time = event[..split].parse().map_err(|_| err())?;
+            let key = u8::from_str_radix(&event[split + 1..], 16).map_err(|_| err())?;
+            if key > 0xF {
+                return Err(err());
+            }
+            events.push(KeyEvent {
+                time,
+                key,
+                pressed: &event[split..split + 1] == "+",
+            });
+        }
+        Ok(Self::new(events))
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    /// Apply the events happening at `time` to the keypad
+    pub fn apply(&self, time: u64, machine: &mut Machine) {
+        let start = self.events.partition_point(|event| event.time < time);
+        for event in self.events[start..].iter().take_while(|e| e.time == time) {
+            machine.keypad.set(event.key, event.pressed);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    pub instructions: u64,
+    pub frames: u64,
+    /// Whether the program executed 00FD before the end of the run
+    pub exited: bool,
+}
+
+/// Run `machine` for the given length, feeding it the scripted keys.
+/// The timers are decremented every `instructions_per_frame` instructions
+/// rather than following the wall clock, so runs are reproducible
+pub fn run(
+    machine: &mut Machine,
+    length: RunLength,
+    instructions_per_frame: u32,
+    keys: &KeyScript,
+) -> Result<RunSummary, MachineError> {
+    let mut summary = RunSummary::default();
+    match length {
+        RunLength::Instructions(count) => {
+            machine.timers.set_clock(TimerClock::Cycles {
+                instructions_per_tick: instructions_per_frame,
+            });
+            while summary.instructions < count {
+                keys.apply(summary.instructions, machine);
+                let outcome = machine.execute_one()?;
+                summary.instructions += 1;
+                if outcome == StepOutcome::Exited {
+                    summary.exited = true;
+                    break;
+                }
+            }
+            summary.frames = summary.instructions / instructions_per_frame.max(1) as u64;
+        }
+        RunLength::Frames(count) => {
+            return run_frames(machine, count, instructions_per_frame, |frame, machine| {
+                keys.apply(frame, machine);
+                true
+            });
+        }
+    }
+    Ok(summary)
+}
+
+/// Run up to `count` frames, calling `before_frame` with the frame number
+/// before each of them. Stops early when it returns false
+fn run_frames<F>(
+    machine: &mut Machine,
+    count: u64,
+    instructions_per_frame: u32,
+    mut before_frame: F,
+) -> Result<RunSummary, MachineError>
+where
+    F: FnMut(u64, &mut Machine) -> bool,
+{
+    let mut summary = RunSummary::default();
+    machine.timers.set_clock(TimerClock::Frames);
+    while summary.frames < count && before_frame(summary.frames, machine) {
+        let frame = machine.run_frame(instructions_per_frame)?;
+        summary.instructions += frame.instructions_executed as u64;
+        summary.frames += 1;
+        if frame.outcome == StepOutcome::Exited {
+            summary.exited = true;
+            break;
+        }
+    }
+    Ok(summary)
+}
+
+/// Same as `run` for `frames` frames, recording the keypad into `movie`,
+/// created with `Movie::new` from `machine`. The movie is finished even when
+/// the machine raises an error, so that the error can be replayed
+pub fn record(
+    machine: &mut Machine,
+    frames: u64,
+    keys: &KeyScript,
+    movie: &mut Movie,
+) -> Result<RunSummary, MachineError> {
+    let result = run_frames(
+        machine,
+        frames,
+        movie.instructions_per_frame,
+        |frame, machine| {
+            keys.apply(frame, machine);
+            movie.record_frame(machine);
+            true
+        },
+    );
+    movie.finish(machine);
+    result
+}
+
+/// Replay `movie` on `machine`, created with `Movie::create_machine`, and
+/// check it ends in the recorded state
+pub fn play(machine: &mut Machine, movie: &Movie) -> Result<RunSummary, MovieError> {
+    let result = run_frames(
+        machine,
+        movie.frames.len() as u64,
+        movie.instructions_per_frame,
+        |frame, machine| movie.apply_frame(frame as usize, machine),
+    );
+    // A desync explains an unexpected error better than the error itself
+    movie.check_final_state(machine)?;
+    result.map_err(MovieError::Machine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::Platform;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_parse_key_script() {
+        let script = KeyScript::parse("20-a, 10+A 30+0").unwrap();
+        assert_eq!(
+            script.events(),
+            &[
+                KeyEvent {
+                    time: 10,
+                    key: 0xA,
+                    pressed: true
+                },
+                KeyEvent {
+                    time: 20,
+                    key: 0xA,
+                    pressed: false
+                },
+                KeyEvent {
+                    time: 30,
+                    key: 0,
+                    pressed: true
+                },
+            ]
+        );
+        for invalid in ["10", "x+1", "10+10", "10*1"] {
+            assert_eq!(
+                KeyScript::parse(invalid),
+                Err(ParseKeyScriptError {
+                    event: invalid.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_with_keys() {
+        // Count frames until key 5 is released, then exit
+        let program = assemble(
+            "
+            : main
+                v1 := 5
+            : wait
+                v0 += 1
+                v2 := key
+                if v2 != v1 then jump wait
+                exit
+            ",
+        )
+        .unwrap();
+        let mut machine = Machine::new(Platform::SuperChip);
+        machine.load_rom_from_bytes(&program.bytes).unwrap();
+        let keys = KeyScript::parse("3+5 5-5").unwrap();
+        let summary = run(&mut machine, RunLength::Frames(100), 10, &keys).unwrap();
+        assert!(summary.exited);
+        assert_eq!(summary.frames, 6);
+        assert_eq!(machine.registers[0], 1);
+
+        let mut machine = Machine::new(Platform::SuperChip);
+        machine.load_rom_from_bytes(&program.bytes).unwrap();
+        let summary = run(
+            &mut machine,
+            RunLength::Instructions(50),
+            10,
+            &KeyScript::default(),
+        )
+        .unwrap();
+        assert_eq!(summary.instructions, 50);
+        assert!(!summary.exited);
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        // Draws random sprites where the last key released says
+        let program = assemble(
+            "
+            : main
+                v0 := key
+                v1 := random 0x3F
+                i := hex v0
+                sprite v0 v1 5
+                jump main
+            ",
+        )
+        .unwrap();
+        let rom = Rom::from_bytes(program.bytes).unwrap();
+        let keys = KeyScript::parse("2+1 3-1 10+5 10-5 20+A 30-A").unwrap();
+
+        let mut machine = Machine::with_seed(Platform::Chip8, 1234);
+        machine.load_rom(rom.clone()).unwrap();
+        let mut movie = Movie::new(&machine, 8);
+        let summary = record(&mut machine, 40, &keys, &mut movie).unwrap();
+        assert_eq!(summary.frames, 40);
+        assert_eq!(movie.frames.len(), 40);
+        // Pressed and released before frame 10 even started
+        assert_eq!(movie.frames[10].held, 0);
+        assert_eq!(movie.frames[10].released, 1 << 5);
+
+        let movie = Movie::from_json(&movie.to_json()).unwrap();
+        let mut replay = movie.create_machine(rom.clone()).unwrap();
+        assert_eq!(play(&mut replay, &movie).unwrap(), summary);
+        assert_eq!(replay.save_state(), machine.save_state());
+
+        // Other inputs end up in another state
+        let mut tampered = movie.clone();
+        tampered.frames[20].released = 1 << 3;
+        let mut replay = tampered.create_machine(rom).unwrap();
+        assert!(matches!(
+            play(&mut replay, &tampered),
+            Err(MovieError::Desync { .. })
+        ));
+
+        let other_rom = Rom::from_bytes(vec![0x12, 0x00]).unwrap();
+        assert!(matches!(
+            movie.create_machine(other_rom),
+            Err(MovieError::RomMismatch { .. })
+        ));
+        let json = movie.to_json().replace("\"version\": 1", "\"version\": 7");
+        assert!(matches!(
+            Movie::from_json(&json),
+            Err(MovieError::UnsupportedVersion(7))
+        ));
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug)]
+use crate::machine::Platform;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Zero,
     ClearScreen,
@@ -35,7 +37,25 @@ pub enum Instruction {
     ConvertToDecimal(u8),
     RegistersToMemory(u8),
     MemoryToRegisters(u8),
-    Unknown(u16, String),
+    // SUPER-CHIP 1.1
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    BigFontCharacter(u8),
+    SaveFlags(u8),
+    LoadFlags(u8),
+    // XO-CHIP
+    ScrollUp(u8),
+    /// F000 NNNN, where NNNN is stored in the two bytes following the opcode
+    LoadLongIndex,
+    SaveRange(u8, u8),
+    LoadRange(u8, u8),
+    SelectPlanes(u8),
+    /// Any opcode not listed above, kept as is
+    Unknown(u16),
 }
 
 trait NibbleDecoder {
@@ -72,13 +92,130 @@ impl NibbleDecoder for u16 {
     }
 }
 
-pub fn decode(bytes: u16, location_int: &str) -> Instruction {
+/// Build an opcode from its 4 nibbles, from the most significant one
+fn nibbles(a: u8, b: u8, c: u8, d: u8) -> u16 {
+    ((a as u16 & 0xF) << 12) | ((b as u16 & 0xF) << 8) | ((c as u16 & 0xF) << 4) | (d as u16 & 0xF)
+}
+
+/// Build an opcode from a nibble, a register and a byte, like 6XNN
+fn nibble_reg_byte(a: u8, x: u8, nn: u8) -> u16 {
+    nibbles(a, x, 0, 0) | nn as u16
+}
+
+/// Build an opcode from a nibble and an address, like 1NNN
+fn nibble_addr(a: u8, nnn: u16) -> u16 {
+    nibbles(a, 0, 0, 0) | (nnn & 0x0FFF)
+}
+
+impl Instruction {
+    /// The first platform with this instruction
+    pub fn platform(&self) -> Platform {
+        use Instruction::*;
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | LowRes | HighRes
+            | BigFontCharacter(_) | SaveFlags(_) | LoadFlags(_) => Platform::SuperChip,
+            ScrollUp(_) | LoadLongIndex | SaveRange(..) | LoadRange(..) | SelectPlanes(_) => {
+                Platform::XoChip
+            }
+            _ => Platform::Chip8,
+        }
+    }
+
+    /// The opcode this instruction decodes from, so that
+    /// `decode(instruction.encode(), Platform::XoChip) == instruction`.
+    /// Fields are truncated to their size in the opcode, e.g. registers to a
+    /// nibble. For `LoadLongIndex`, this is only the first word: the address
+    /// follows in the next two bytes
+    pub fn encode(&self) -> u16 {
+        use Instruction::*;
+        match *self {
+            Zero => 0x0000,
+            ClearScreen => 0x00E0,
+            Return => 0x00EE,
+            ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowRes => 0x00FE,
+            HighRes => 0x00FF,
+            Jump(nnn) => nibble_addr(0x1, nnn),
+            Subroutine(nnn) => nibble_addr(0x2, nnn),
+            SkipIfEqualRegVal(x, nn) => nibble_reg_byte(0x3, x, nn),
+            SkipIfNotEqualRegVal(x, nn) => nibble_reg_byte(0x4, x, nn),
+            SkipIfEqualRegReg(x, y) => nibbles(0x5, x, y, 0x0),
+            SaveRange(x, y) => nibbles(0x5, x, y, 0x2),
+            LoadRange(x, y) => nibbles(0x5, x, y, 0x3),
+            SetRegToVal(x, nn) => nibble_reg_byte(0x6, x, nn),
+            AddValToReg(x, nn) => nibble_reg_byte(0x7, x, nn),
+            Set(x, y) => nibbles(0x8, x, y, 0x0),
+            Or(x, y) => nibbles(0x8, x, y, 0x1),
+            And(x, y) => nibbles(0x8, x, y, 0x2),
+            Xor(x, y) => nibbles(0x8, x, y, 0x3),
+            Add(x, y) => nibbles(0x8, x, y, 0x4),
+            SubtractXY(x, y) => nibbles(0x8, x, y, 0x5),
+            ShiftRight(x, y) => nibbles(0x8, x, y, 0x6),
+            SubtractYX(x, y) => nibbles(0x8, x, y, 0x7),
+            ShiftLeft(x, y) => nibbles(0x8, x, y, 0xE),
+            SkipIfNotEqualRegReg(x, y) => nibbles(0x9, x, y, 0x0),
+            SetIndexRegister(nnn) => nibble_addr(0xA, nnn),
+            JumpWithOffset(nnn) => nibble_addr(0xB, nnn),
+            Random(x, nn) => nibble_reg_byte(0xC, x, nn),
+            Display(x, y, n) => nibbles(0xD, x, y, n),
+            SkipIfKeyPressed(x) => nibble_reg_byte(0xE, x, 0x9E),
+            SkipIfKeyNotPressed(x) => nibble_reg_byte(0xE, x, 0xA1),
+            LoadLongIndex => 0xF000,
+            SelectPlanes(x) => nibble_reg_byte(0xF, x, 0x01),
+            ReadDelayTimer(x) => nibble_reg_byte(0xF, x, 0x07),
+            GetKey(x) => nibble_reg_byte(0xF, x, 0x0A),
+            SetDelayTimer(x) => nibble_reg_byte(0xF, x, 0x15),
+            SetSoundTimer(x) => nibble_reg_byte(0xF, x, 0x18),
+            AddToIndex(x) => nibble_reg_byte(0xF, x, 0x1E),
+            FontCharacter(x) => nibble_reg_byte(0xF, x, 0x29),
+            BigFontCharacter(x) => nibble_reg_byte(0xF, x, 0x30),
+            ConvertToDecimal(x) => nibble_reg_byte(0xF, x, 0x33),
+            RegistersToMemory(x) => nibble_reg_byte(0xF, x, 0x55),
+            MemoryToRegisters(x) => nibble_reg_byte(0xF, x, 0x65),
+            SaveFlags(x) => nibble_reg_byte(0xF, x, 0x75),
+            LoadFlags(x) => nibble_reg_byte(0xF, x, 0x85),
+            Unknown(opcode) => opcode,
+        }
+    }
+}
+
+/// Decode an opcode, as `Unknown` if it's only defined by an extension that
+/// `platform` doesn't have
+pub fn decode(bytes: u16, platform: Platform) -> Instruction {
+    let instruction = decode_any(bytes);
+    if instruction.platform() > platform {
+        Instruction::Unknown(bytes)
+    } else {
+        instruction
+    }
+}
+
+/// Decode an opcode of any platform
+fn decode_any(bytes: u16) -> Instruction {
     if bytes == 0 {
         Instruction::Zero
     } else if bytes == 0x00E0 {
         Instruction::ClearScreen
     } else if bytes == 0x00EE {
         Instruction::Return
+    } else if bytes & 0xFFF0 == 0x00C0 {
+        Instruction::ScrollDown(bytes.n())
+    } else if bytes & 0xFFF0 == 0x00D0 {
+        Instruction::ScrollUp(bytes.n())
+    } else if bytes == 0x00FB {
+        Instruction::ScrollRight
+    } else if bytes == 0x00FC {
+        Instruction::ScrollLeft
+    } else if bytes == 0x00FD {
+        Instruction::Exit
+    } else if bytes == 0x00FE {
+        Instruction::LowRes
+    } else if bytes == 0x00FF {
+        Instruction::HighRes
     } else if bytes.category() == 1 {
         Instruction::Jump(bytes.nnn())
     } else if bytes.category() == 2 {
@@ -87,8 +224,12 @@ pub fn decode(bytes: u16, location_int: &str) -> Instruction {
         Instruction::SkipIfEqualRegVal(bytes.vx(), bytes.nn())
     } else if bytes.category() == 4 {
         Instruction::SkipIfNotEqualRegVal(bytes.vx(), bytes.nn())
-    } else if bytes.category() == 5 {
+    } else if bytes.category() == 5 && bytes.n() == 0 {
         Instruction::SkipIfEqualRegReg(bytes.vx(), bytes.vy())
+    } else if bytes.category() == 5 && bytes.n() == 2 {
+        Instruction::SaveRange(bytes.vx(), bytes.vy())
+    } else if bytes.category() == 5 && bytes.n() == 3 {
+        Instruction::LoadRange(bytes.vx(), bytes.vy())
     } else if bytes.category() == 6 {
         Instruction::SetRegToVal(bytes.vx(), bytes.nn())
     } else if bytes.category() == 7 {
@@ -111,7 +252,7 @@ pub fn decode(bytes: u16, location_int: &str) -> Instruction {
         Instruction::SubtractYX(bytes.vx(), bytes.vy())
     } else if bytes.category() == 8 && bytes.n() == 0xE {
         Instruction::ShiftLeft(bytes.vx(), bytes.vy())
-    } else if bytes.category() == 9 {
+    } else if bytes.category() == 9 && bytes.n() == 0 {
         Instruction::SkipIfNotEqualRegReg(bytes.vx(), bytes.vy())
     } else if bytes.category() == 0xA {
         Instruction::SetIndexRegister(bytes.nnn())
@@ -125,6 +266,10 @@ pub fn decode(bytes: u16, location_int: &str) -> Instruction {
         Instruction::SkipIfKeyPressed(bytes.vx())
     } else if bytes.category() == 0xE && bytes.nn() == 0xA1 {
         Instruction::SkipIfKeyNotPressed(bytes.vx())
+    } else if bytes == 0xF000 {
+        Instruction::LoadLongIndex
+    } else if bytes.category() == 0xF && bytes.nn() == 0x01 {
+        Instruction::SelectPlanes(bytes.vx())
     } else if bytes.category() == 0xF && bytes.nn() == 0x07 {
         Instruction::ReadDelayTimer(bytes.vx())
     } else if bytes.category() == 0xF && bytes.nn() == 0x15 {
@@ -137,13 +282,79 @@ pub fn decode(bytes: u16, location_int: &str) -> Instruction {
         Instruction::GetKey(bytes.vx())
     } else if bytes.category() == 0xF && bytes.nn() == 0x29 {
         Instruction::FontCharacter(bytes.vx())
+    } else if bytes.category() == 0xF && bytes.nn() == 0x30 {
+        Instruction::BigFontCharacter(bytes.vx())
     } else if bytes.category() == 0xF && bytes.nn() == 0x33 {
         Instruction::ConvertToDecimal(bytes.vx())
     } else if bytes.category() == 0xF && bytes.nn() == 0x55 {
         Instruction::RegistersToMemory(bytes.vx())
     } else if bytes.category() == 0xF && bytes.nn() == 0x65 {
         Instruction::MemoryToRegisters(bytes.vx())
+    } else if bytes.category() == 0xF && bytes.nn() == 0x75 {
+        Instruction::SaveFlags(bytes.vx())
+    } else if bytes.category() == 0xF && bytes.nn() == 0x85 {
+        Instruction::LoadFlags(bytes.vx())
     } else {
-        Instruction::Unknown(bytes, location_int.to_string())
+        Instruction::Unknown(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        for opcode in 0..=u16::MAX {
+            let instruction = decode(opcode, Platform::XoChip);
+            assert_eq!(instruction.encode(), opcode, "{:?}", instruction);
+            assert_eq!(decode(instruction.encode(), Platform::XoChip), instruction);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Instruction::SetRegToVal(0xA, 0x42).encode(), 0x6A42);
+        assert_eq!(Instruction::Display(1, 2, 0xF).encode(), 0xD12F);
+        assert_eq!(Instruction::Jump(0x345).encode(), 0x1345);
+        assert_eq!(Instruction::GetKey(3).encode(), 0xF30A);
+        assert_eq!(Instruction::SaveRange(2, 5).encode(), 0x5252);
+        // Out of range fields are truncated
+        assert_eq!(Instruction::Jump(0x1345).encode(), 0x1345);
+    }
+
+    #[test]
+    fn test_decode_9xyn() {
+        assert_eq!(
+            decode(0x9120, Platform::Chip8),
+            Instruction::SkipIfNotEqualRegReg(1, 2)
+        );
+        assert_eq!(
+            decode(0x9121, Platform::Chip8),
+            Instruction::Unknown(0x9121)
+        );
+    }
+
+    #[test]
+    fn test_decode_per_platform() {
+        for (opcode, platform) in [
+            (0x00FF, Platform::SuperChip),
+            (0xF175, Platform::SuperChip),
+            (0x00D2, Platform::XoChip),
+            (0x5122, Platform::XoChip),
+            (0xF000, Platform::XoChip),
+            (0xF201, Platform::XoChip),
+        ] {
+            assert_eq!(decode(opcode, platform).platform(), platform);
+            for older in [Platform::Chip8, Platform::SuperChip] {
+                if older < platform {
+                    assert_eq!(decode(opcode, older), Instruction::Unknown(opcode));
+                }
+            }
+        }
+        assert_eq!(
+            decode(0xD120, Platform::Chip8),
+            Instruction::Display(1, 2, 0)
+        );
     }
 }
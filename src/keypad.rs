@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+/// The 16 keys hexadecimal keypad, fed by press and release events.
+/// See layout at https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#keypad
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keypad {
+    held: [bool; 16],
+    /// Bitmask of the keys released since the last call to `clear_released`
+    released: u16,
+}
+
+impl Keypad {
+    pub fn press(&mut self, key: u8) {
+        self.held[key as usize & 0xF] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        let key = key as usize & 0xF;
+        if self.held[key] {
+            self.released |= 1 << key;
+        }
+        self.held[key] = false;
+    }
+
+    pub fn set(&mut self, key: u8, pressed: bool) {
+        if pressed {
+            self.press(key);
+        } else {
+            self.release(key);
+        }
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.held[key as usize & 0xF]
+    }
+
+    /// Which keys are currently held, indexed by key
+    pub fn held(&self) -> &[bool; 16] {
+        &self.held
+    }
+
+    /// Bitmask of the held keys, bit N being key N
+    pub fn held_mask(&self) -> u16 {
+        self.held
+            .iter()
+            .enumerate()
+            .map(|(key, &held)| (held as u16) << key)
+            .sum()
+    }
+
+    /// Bitmask of the releases not consumed by `take_released` yet
+    pub fn released_mask(&self) -> u16 {
+        self.released
+    }
+
+    /// Set the held keys and pending releases at once, e.g. to replay a
+    /// recording
+    pub fn set_masks(&mut self, held: u16, released: u16) {
+        for (key, held_key) in self.held.iter_mut().enumerate() {
+            *held_key = (held >> key) & 1 == 1;
+        }
+        self.released = released;
+    }
+
+    /// Release all keys, e.g. when the window loses focus
+    pub fn release_all(&mut self) {
+        for key in 0..16 {
+            self.release(key);
+        }
+    }
+
+    /// Forget about previous releases, so that `take_released` only reports
+    /// keys released from now on
+    pub fn clear_released(&mut self) {
+        self.released = 0;
+    }
+
+    /// The lowest key released since the last call to `clear_released`,
+    /// consuming that release
+    pub fn take_released(&mut self) -> Option<u8> {
+        if self.released == 0 {
+            return None;
+        }
+        let key = self.released.trailing_zeros() as u8;
+        self.released &= !(1 << key);
+        Some(key)
+    }
+}
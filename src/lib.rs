@@ -1,4 +1,20 @@
+pub mod analysis;
 pub mod array2d;
+pub mod assembler;
+pub mod clock;
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
+pub mod dump;
+pub mod gdb;
+pub mod headless;
 pub mod instructions;
+pub mod keypad;
 pub mod machine;
+pub mod movie;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod rom;
+pub mod savestate;
 pub mod texture;
@@ -1,118 +1,507 @@
 use crate::array2d::Array2D;
+use crate::clock::TimerClock;
 use crate::instructions::{decode, Instruction};
+use crate::keypad::Keypad;
+use crate::quirks::{Quirks, SpriteEdges};
+use crate::rng::MachineRng;
+use crate::rom::{Rom, RomLoadError, ROM_START_ADDRESS};
+use crate::savestate::{DisplayState, SaveState, SaveStateError, TimersState, SAVE_STATE_VERSION};
 use crate::texture::RGBAImage;
 use rand::Rng;
-use std::fs::File;
-use std::io;
-use std::io::Read;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::num::Wrapping;
-use std::time::SystemTime;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
 
-const DISPLAY_WIDTH: usize = 64;
-const DISPLAY_HEIGHT: usize = 32;
+pub(crate) const LORES_DISPLAY_WIDTH: usize = 64;
+pub(crate) const LORES_DISPLAY_HEIGHT: usize = 32;
+pub(crate) const HIRES_DISPLAY_WIDTH: usize = 128;
+pub(crate) const HIRES_DISPLAY_HEIGHT: usize = 64;
 
 const FONT_START_ADDRESS: usize = 0x50;
+const BIG_FONT_START_ADDRESS: usize = FONT_START_ADDRESS + FONT.len();
 
-const ROM_START_ADDRESS: usize = 0x200;
+/// Number of XO-CHIP bitplanes. CHIP-8 and SUPER-CHIP only ever use the first one
+pub const NUM_PLANES: usize = 2;
+
+/// RGBA color for each combination of lit planes (bit 0 is plane 0 and bit 1 plane 1)
+pub type Palette = [[u8; 4]; 1 << NUM_PLANES];
+
+pub const DEFAULT_PALETTE: Palette = [
+    [0, 0, 0, 255],
+    [255, 255, 255, 255],
+    [170, 170, 170, 255],
+    [85, 85, 85, 255],
+];
 
 pub struct Display {
-    _pixels: Array2D<bool>,
+    _planes: [Array2D<bool>; NUM_PLANES],
+    /// RGBA view of the planes, only brought up to date by `rgba`
     _rgba: Vec<u8>,
+    /// Bitmask of the rows whose RGBA is out of date. The tallest display is
+    /// 64 rows so this fits in a u64
+    dirty_rows: u64,
+    /// Bitmask of the planes drawing, clearing and scrolling operate on (XO-CHIP FN01)
+    selected_planes: u8,
+    palette: Palette,
+    /// Incremented every time the content of the display changes
+    generation: u64,
 }
 
 impl Default for Display {
     fn default() -> Self {
-        let mut display = Display {
-            _pixels: Array2D::new(DISPLAY_HEIGHT, DISPLAY_WIDTH, || false),
-            _rgba: vec![0; DISPLAY_HEIGHT * DISPLAY_WIDTH * 4],
-        };
-        display.update_rgba_from_pixels();
-        display
+        Display::new(LORES_DISPLAY_WIDTH, LORES_DISPLAY_HEIGHT)
     }
 }
 
 impl Display {
-    pub fn to_image(&self) -> RGBAImage {
-        RGBAImage::new(self._rgba.clone(), self._pixels.cols(), self._pixels.rows())
+    fn new(width: usize, height: usize) -> Self {
+        Display {
+            _planes: [
+                Array2D::new(height, width, || false),
+                Array2D::new(height, width, || false),
+            ],
+            _rgba: vec![0; height * width * 4],
+            dirty_rows: u64::MAX,
+            selected_planes: 1,
+            palette: DEFAULT_PALETTE,
+            generation: 0,
+        }
+    }
+
+    /// A copy of the RGBA view. Prefer comparing `generation` with a
+    /// previous value first to avoid copying an unchanged image
+    pub fn to_image(&mut self) -> RGBAImage {
+        let data = self.rgba().to_vec();
+        RGBAImage::new(data, self.width(), self.height())
+    }
+
+    /// The display as RGBA bytes, row by row. Only the rows that changed since
+    /// the previous call are converted
+    pub fn rgba(&mut self) -> &[u8] {
+        let width = self.width();
+        for i in 0..self.height() {
+            if (self.dirty_rows >> i) & 1 == 0 {
+                continue;
+            }
+            for j in 0..width {
+                let color = self.palette[self.color_index(j, i)];
+                let offset = (i * width * 4) + j * 4;
+                self._rgba[offset..offset + 4].copy_from_slice(&color);
+            }
+        }
+        self.dirty_rows = 0;
+        &self._rgba
+    }
+
+    /// Bitmask of the rows that changed since the last call to `rgba`
+    pub fn dirty_rows(&self) -> u64 {
+        self.dirty_rows
     }
 
+    /// The pixels of the first plane, which is the only one outside of XO-CHIP
     pub fn pixels(&self) -> &Array2D<bool> {
-        &self._pixels
+        &self._planes[0]
+    }
+
+    pub fn plane(&self, plane: usize) -> &Array2D<bool> {
+        &self._planes[plane]
     }
 
     pub fn pixel(&self, x: usize, y: usize) -> bool {
-        self._pixels[(y, x)]
+        self._planes[0][(y, x)]
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, v: bool) {
-        self._pixels[(y, x)] = v;
-        self.update_rgba_from_pixels();
+        if self._planes[0][(y, x)] != v {
+            self._planes[0][(y, x)] = v;
+            self.mark_dirty(1 << y);
+        }
+    }
+
+    /// Index in the palette of the given pixel, combining all planes
+    pub fn color_index(&self, x: usize, y: usize) -> usize {
+        self._planes
+            .iter()
+            .enumerate()
+            .map(|(i, plane)| (plane[(y, x)] as usize) << i)
+            .sum()
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
     }
 
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.mark_dirty(u64::MAX);
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    pub fn select_planes(&mut self, mask: u8) {
+        self.selected_planes = mask & ((1 << NUM_PLANES) - 1) as u8;
+    }
+
+    fn is_plane_selected(&self, plane: usize) -> bool {
+        (self.selected_planes >> plane) & 1 == 1
+    }
+
+    /// Clear the selected planes
     pub fn clear(&mut self) {
-        for i in 0..DISPLAY_HEIGHT {
-            for j in 0..DISPLAY_WIDTH {
-                self._pixels[(i, j)] = false;
+        for p in 0..NUM_PLANES {
+            if !self.is_plane_selected(p) {
+                continue;
+            }
+            for i in 0..self.height() {
+                for j in 0..self.width() {
+                    self._planes[p][(i, j)] = false;
+                }
             }
         }
-        self.update_rgba_from_pixels();
+        if self.selected_planes != 0 {
+            self.mark_dirty(u64::MAX);
+        }
+    }
+
+    /// Whether we are in SUPER-CHIP 128x64 high resolution mode
+    pub fn is_hires(&self) -> bool {
+        self.width() == HIRES_DISPLAY_WIDTH
+    }
+
+    /// Switch between 64x32 and 128x64. Like Octo, this clears all planes
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT)
+        } else {
+            (LORES_DISPLAY_WIDTH, LORES_DISPLAY_HEIGHT)
+        };
+        *self = Display {
+            selected_planes: self.selected_planes,
+            palette: self.palette,
+            generation: self.generation,
+            ..Display::new(width, height)
+        };
+        self.mark_dirty(u64::MAX);
     }
 
-    fn update_rgba_from_pixels(&mut self) {
-        for i in 0..DISPLAY_HEIGHT {
-            for j in 0..DISPLAY_WIDTH {
-                let color = if self._pixels[(i, j)] { 255 } else { 0 };
-                self._rgba[(i * DISPLAY_WIDTH * 4) + j * 4] = color;
-                self._rgba[(i * DISPLAY_WIDTH * 4) + j * 4 + 1] = color;
-                self._rgba[(i * DISPLAY_WIDTH * 4) + j * 4 + 2] = color;
-                self._rgba[(i * DISPLAY_WIDTH * 4) + j * 4 + 3] = color;
+    /// XOR a sprite on the given plane, with `edges` telling what to do with
+    /// the pixels past the right and bottom edges.
+    /// Each row is `width` bits wide, stored in the least significant bits of
+    /// the `u16`, with the leftmost pixel in the most significant of them.
+    /// Returns true if any pixel was turned off
+    pub fn draw_sprite(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        rows: &[u16],
+        width: usize,
+        edges: SpriteEdges,
+    ) -> bool {
+        let x = x % self.width();
+        let y = y % self.height();
+        let mut collision = false;
+        let mut dirty_rows = 0;
+        for (i, row) in rows.iter().enumerate() {
+            let mut py = y + i;
+            if py >= self.height() {
+                match edges {
+                    SpriteEdges::Clip => break,
+                    SpriteEdges::Wrap => py %= self.height(),
+                }
             }
+            for j in 0..width {
+                let mut px = x + j;
+                if px >= self.width() {
+                    match edges {
+                        SpriteEdges::Clip => break,
+                        SpriteEdges::Wrap => px %= self.width(),
+                    }
+                }
+                if (row >> (width - 1 - j)) & 1 == 0 {
+                    continue;
+                }
+                let pixel = &mut self._planes[plane][(py, px)];
+                collision |= *pixel;
+                *pixel = !*pixel;
+                dirty_rows |= 1 << py;
+            }
+        }
+        self.mark_dirty(dirty_rows);
+        collision
+    }
+
+    /// Scroll the selected planes by the given number of pixels, filling with
+    /// blank pixels. Positive `dx` moves right and positive `dy` moves down
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        for p in 0..NUM_PLANES {
+            if !self.is_plane_selected(p) {
+                continue;
+            }
+            let previous = self._planes[p].clone();
+            for i in 0..height {
+                for j in 0..width {
+                    let (src_i, src_j) = (i - dy, j - dx);
+                    self._planes[p][(i as usize, j as usize)] = (0..height).contains(&src_i)
+                        && (0..width).contains(&src_j)
+                        && previous[(src_i as usize, src_j as usize)];
+                }
+            }
+        }
+        if self.selected_planes != 0 && (dx != 0 || dy != 0) {
+            self.mark_dirty(u64::MAX);
+        }
+    }
+
+    /// A counter incremented every time the display content changes, which
+    /// can be compared to a previous value to know if a redraw is needed
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn mark_dirty(&mut self, rows: u64) {
+        if rows != 0 {
+            self.dirty_rows |= rows;
+            self.generation += 1;
+        }
+    }
+
+    fn save_state(&self) -> DisplayState {
+        DisplayState {
+            hires: self.is_hires(),
+            selected_planes: self.selected_planes,
+            palette: self.palette,
+            planes: self._planes.to_vec(),
+        }
+    }
+
+    /// Replace the content with a validated state. This counts as a change,
+    /// so the whole display is redrawn
+    fn load_state(&mut self, state: &DisplayState) {
+        let (height, width) = (state.planes[0].rows(), state.planes[0].cols());
+        *self = Display {
+            generation: self.generation,
+            ..Display::new(width, height)
+        };
+        for (plane, saved) in self._planes.iter_mut().zip(&state.planes) {
+            plane.clone_from(saved);
         }
+        self.selected_planes = state.selected_planes;
+        self.palette = state.palette;
+        self.mark_dirty(u64::MAX);
     }
 
     pub fn width(&self) -> usize {
-        self._pixels.cols()
+        self._planes[0].cols()
     }
 
     pub fn height(&self) -> usize {
-        self._pixels.rows()
+        self._planes[0].rows()
     }
 }
 
 pub struct Timers {
     pub delay: u8,
     pub sound: u8,
-    last_tick: SystemTime,
+    clock: TimerClock,
+    /// When we last ticked, as given by the time source of `TimerClock::Time`
+    last_tick: Duration,
     last_tick_remainder: f64,
+    /// Instructions executed since we last ticked, for `TimerClock::Cycles`
+    cycles_since_tick: u32,
 }
 
 impl Default for Timers {
     fn default() -> Self {
-        Self {
+        Timers::new(TimerClock::default())
+    }
+}
+
+impl Timers {
+    pub fn new(clock: TimerClock) -> Self {
+        let mut timers = Self {
             delay: 0,
             sound: 0,
-            last_tick: now(),
+            clock: TimerClock::Frames,
+            last_tick: Duration::ZERO,
             last_tick_remainder: 0.0,
+            cycles_since_tick: 0,
+        };
+        timers.set_clock(clock);
+        timers
+    }
+
+    pub fn clock(&self) -> &TimerClock {
+        &self.clock
+    }
+
+    /// Change what drives the timers. Time elapsed or instructions executed
+    /// under the previous clock are discarded
+    pub fn set_clock(&mut self, mut clock: TimerClock) {
+        self.last_tick = match &mut clock {
+            TimerClock::Time(source) => source.elapsed(),
+            _ => Duration::ZERO,
+        };
+        self.last_tick_remainder = 0.0;
+        self.cycles_since_tick = 0;
+        self.clock = clock;
+    }
+
+    /// Decrement both timers by one, as happens 60 times per second
+    pub fn decrement(&mut self) {
+        self.decrement_by(1);
+    }
+
+    fn decrement_by(&mut self, n: u32) {
+        let n = n.min(u8::MAX as u32) as u8;
+        self.delay = self.delay.saturating_sub(n);
+        self.sound = self.sound.saturating_sub(n);
+    }
+
+    fn save_state(&self) -> TimersState {
+        TimersState {
+            delay: self.delay,
+            sound: self.sound,
+            tick_remainder: self.last_tick_remainder,
+            cycles_since_tick: self.cycles_since_tick,
         }
     }
-}
 
-impl Timers {
+    /// Restore the timers, keeping the current clock. Time that elapsed
+    /// before the restore isn't counted
+    fn load_state(&mut self, state: &TimersState) {
+        if let TimerClock::Time(source) = &mut self.clock {
+            self.last_tick = source.elapsed();
+        }
+        self.delay = state.delay;
+        self.sound = state.sound;
+        self.last_tick_remainder = state.tick_remainder;
+        self.cycles_since_tick = state.cycles_since_tick;
+    }
+
+    /// Called after each executed instruction
     fn tick(&mut self) {
-        // Timers are decremented by 1 at 60hz
-        let time = now();
-        let elapsed_s = time.duration_since(self.last_tick).unwrap().as_secs_f64();
-        let decrement = self.last_tick_remainder + elapsed_s * 60.0;
-        let rounded_decrement = decrement.floor();
-        // Store the decimal part that we couldn't subtract this tick
-        self.last_tick_remainder = decrement - rounded_decrement;
-        self.delay = (self.delay as f64 - rounded_decrement)
-            .floor()
-            .clamp(0.0, 255.0) as u8;
-        self.sound = (self.sound as f64 - rounded_decrement)
-            .floor()
-            .clamp(0.0, 255.0) as u8;
-        self.last_tick = time;
+        match &mut self.clock {
+            TimerClock::Time(source) => {
+                // Timers are decremented by 1 at 60hz
+                let time = source.elapsed();
+                let elapsed_s = time.saturating_sub(self.last_tick).as_secs_f64();
+                let decrement = self.last_tick_remainder + elapsed_s * 60.0;
+                let rounded_decrement = decrement.floor();
+                // Store the decimal part that we couldn't subtract this tick
+                self.last_tick_remainder = decrement - rounded_decrement;
+                self.last_tick = time;
+                self.decrement_by(rounded_decrement as u32);
+            }
+            TimerClock::Cycles {
+                instructions_per_tick,
+            } => {
+                self.cycles_since_tick += 1;
+                if self.cycles_since_tick >= *instructions_per_tick {
+                    self.cycles_since_tick = 0;
+                    self.decrement();
+                }
+            }
+            TimerClock::Frames => {}
+        }
+    }
+}
+
+/// What happened when executing one instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Executed,
+    /// FX0A is blocking until a key is pressed
+    WaitingForKey,
+    /// The program exited through 00FD
+    Exited,
+}
+
+/// Data read or written in RAM by an instruction, not counting fetching the
+/// instruction itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read(Range<usize>),
+    Write(Range<usize>),
+}
+
+/// What happened during a call to `Machine::run_frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameResult {
+    /// Lower than what was asked if the frame was cut short by FX0A or 00FD
+    pub instructions_executed: u32,
+    /// Outcome of the last instruction of the frame
+    pub outcome: StepOutcome,
+    pub display_changed: bool,
+}
+
+/// Errors stopping the execution of an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineError {
+    StackOverflow,
+    StackUnderflow,
+    UnknownOpcode { pc: usize, opcode: u16 },
+    MemoryOutOfBounds { addr: usize },
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::StackOverflow => write!(f, "maximum stack depth exceeded"),
+            MachineError::StackUnderflow => write!(f, "trying to pop from empty stack"),
+            MachineError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:#06x} at pc={:#05x}", opcode, pc)
+            }
+            MachineError::MemoryOutOfBounds { addr } => {
+                write!(f, "memory access out of bounds at {:#06x}", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MachineError {}
+
+/// The CHIP-8 extension a program targets. Each one extends the previous one,
+/// so they're ordered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    /// https://johnearnest.github.io/Octo/docs/XO-ChipSpecification.html
+    XoChip,
+}
+
+impl Platform {
+    /// Guess the platform from the file extensions used by Octo: .sc8 for
+    /// SUPER-CHIP, .xo8 for XO-CHIP and anything else for CHIP-8
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Platform {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("xo8") => Platform::XoChip,
+            Some("sc8") => Platform::SuperChip,
+            _ => Platform::Chip8,
+        }
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// The quirks a program written for this platform most likely expects
+    pub fn default_quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::modern(),
+            Platform::SuperChip => Quirks::superchip(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
     }
 }
 
@@ -120,35 +509,72 @@ impl Timers {
 /// https://tobiasvl.github.io/blog/write-a-chip-8-emulator/
 pub struct Machine {
     pub display: Display,
-    pub ram: [u8; 4096],
+    pub platform: Platform,
+    pub ram: Vec<u8>,
     pub stack: [u16; 100],
     stack_index: usize,
     pub program_counter: usize,
     pub index_register: u16,
     pub registers: [u8; 16],
-    pub key_pressed: [bool; 16],
+    pub keypad: Keypad,
+    /// Whether FX0A started waiting for a key release
+    pub waiting_for_key: bool,
     pub timers: Timers,
+    pub quirks: Quirks,
+    /// SUPER-CHIP "RPL user flags", persisted by FX75 and restored by FX85
+    pub rpl_flags: [u8; 16],
+    /// Set once the program executed 00FD, after which nothing is executed
+    pub halted: bool,
+    pub rng: MachineRng,
+    /// The ROM that was last loaded
+    pub rom: Option<Rom>,
+    /// Decoded instruction for each address of `ram` that was executed, so
+    /// that loops don't go through `decode` again. Empty when disabled
+    decode_cache: Vec<Option<Instruction>>,
+    /// RAM accessed by the last executed instruction, for watchpoints
+    memory_access: Option<MemoryAccess>,
 }
 
 impl Default for Machine {
     fn default() -> Self {
+        Machine::new(Platform::Chip8)
+    }
+}
+
+impl Machine {
+    pub fn new(platform: Platform) -> Self {
         let mut machine = Machine {
             display: Display::default(),
-            ram: [0; 4096],
+            platform,
+            ram: vec![0; platform.ram_size()],
             stack: [0; 100],
             stack_index: 0,
             program_counter: 0,
             index_register: 0,
             registers: [0; 16],
-            key_pressed: [false; 16],
+            keypad: Keypad::default(),
+            waiting_for_key: false,
             timers: Timers::default(),
+            quirks: platform.default_quirks(),
+            rpl_flags: [0; 16],
+            halted: false,
+            rng: MachineRng::from_entropy(),
+            rom: None,
+            decode_cache: vec![None; platform.ram_size()],
+            memory_access: None,
         };
         machine.init_font();
         machine
     }
-}
 
-impl Machine {
+    /// A machine whose CXNN random numbers are fully determined by `seed`
+    pub fn with_seed(platform: Platform, seed: u64) -> Self {
+        Machine {
+            rng: MachineRng::new(seed),
+            ..Machine::new(platform)
+        }
+    }
+
     fn set_flag_register(&mut self, v: u8) {
         self.registers[15] = v;
     }
@@ -157,71 +583,253 @@ impl Machine {
         self.registers[15]
     }
 
-    fn push_stack(&mut self, v: u16) {
-        if self.stack_index > self.stack.len() {
-            // TODO: Warning logs / flag to display in UI ?
-            println!("maximum stack depth exceeded ({:?})", self.stack.len());
-            self.stack_index = self.stack.len() - 1;
+    /// Number of return addresses on the stack, i.e. `stack[..stack_pointer()]`
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_index
+    }
+
+    /// Set the number of return addresses on the stack, keeping their values
+    pub fn set_stack_pointer(&mut self, stack_pointer: usize) -> Result<(), MachineError> {
+        if stack_pointer > self.stack.len() {
+            return Err(MachineError::StackOverflow);
+        }
+        self.stack_index = stack_pointer;
+        Ok(())
+    }
+
+    fn push_stack(&mut self, v: u16) -> Result<(), MachineError> {
+        if self.stack_index >= self.stack.len() {
+            return Err(MachineError::StackOverflow);
         }
         self.stack[self.stack_index] = v;
         self.stack_index += 1;
+        Ok(())
     }
 
-    fn pop_stack(&mut self) -> u16 {
+    fn pop_stack(&mut self) -> Result<u16, MachineError> {
         if self.stack_index < 1 {
-            // TODO: Warning logs / flag to display in UI ?
-            println!("trying to pop from empty stack");
-            self.stack_index = 1;
+            return Err(MachineError::StackUnderflow);
         }
         self.stack_index -= 1;
-        self.stack[self.stack_index]
+        Ok(self.stack[self.stack_index])
     }
 
     fn init_font(&mut self) {
         for (i, &data) in FONT.iter().enumerate() {
             self.ram[FONT_START_ADDRESS + i] = data;
         }
+        for (i, &data) in BIG_FONT.iter().enumerate() {
+            self.ram[BIG_FONT_START_ADDRESS + i] = data;
+        }
     }
 
     fn get_character_address(&self, char: u8) -> u16 {
-        FONT_START_ADDRESS as u16 + (char as u16) * 4
+        FONT_START_ADDRESS as u16 + (char as u16) * 5
     }
 
-    /// Load from bytes
-    pub fn load_rom_from_bytes(&mut self, data: &[u8]) {
-        for (i, v) in data.iter().enumerate() {
-            self.ram[ROM_START_ADDRESS + i] = *v;
+    fn get_big_character_address(&self, char: u8) -> u16 {
+        BIG_FONT_START_ADDRESS as u16 + (char as u16) * 10
+    }
+
+    /// Copy the ROM in RAM and point the program counter to it
+    pub fn load_rom(&mut self, rom: Rom) -> Result<(), RomLoadError> {
+        let max_size = self.ram.len() - ROM_START_ADDRESS;
+        if rom.size() > max_size {
+            return Err(RomLoadError::TooLarge {
+                size: rom.size(),
+                max_size,
+            });
         }
+        self.ram[ROM_START_ADDRESS..ROM_START_ADDRESS + rom.size()].copy_from_slice(rom.bytes());
+        self.invalidate_decode_cache();
         self.program_counter = ROM_START_ADDRESS;
+        self.rom = Some(rom);
+        Ok(())
     }
 
-    /// Load from a ROM file
-    pub fn load_rom_from_file(&mut self, filename: &str) -> io::Result<()> {
-        let mut f = File::open(filename)?;
-        let mut buf: Vec<u8> = vec![];
-        f.read_to_end(&mut buf)?;
+    /// Load from bytes
+    pub fn load_rom_from_bytes(&mut self, data: &[u8]) -> Result<(), RomLoadError> {
+        self.load_rom(Rom::from_bytes(data.to_vec())?)
+    }
 
-        self.load_rom_from_bytes(&buf);
-        Ok(())
+    /// Load from a ROM file
+    pub fn load_rom_from_file(&mut self, filename: &str) -> Result<(), RomLoadError> {
+        self.load_rom(Rom::from_file(filename)?)
     }
 
     /// A helper to load from 2 bytes at a time, which make it easy to write
     /// instructions in hexa; mostly for testing
-    pub fn load_rom_from_instrhex(&mut self, data: &[u16]) {
+    pub fn load_rom_from_instrhex(&mut self, data: &[u16]) -> Result<(), RomLoadError> {
         let mut bytes: Vec<u8> = vec![];
         for v in data.iter() {
             bytes.push(((v & 0xFF00) >> 8) as u8);
             bytes.push((v & 0x00FF) as u8);
         }
-        self.load_rom_from_bytes(&bytes);
+        self.load_rom_from_bytes(&bytes)
+    }
+
+    /// Check that `len` bytes starting at `address` are all in RAM
+    fn check_ram_range(&self, address: usize, len: usize) -> Result<(), MachineError> {
+        if address + len > self.ram.len() {
+            Err(MachineError::MemoryOutOfBounds {
+                addr: address.max(self.ram.len()),
+            })
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn decode_next_instruction(&self) -> Instruction {
-        decode(
-            ((self.ram[self.program_counter] as u16) << 8)
-                | self.ram[self.program_counter + 1] as u16,
-            &format!("pc={:#02x}", self.program_counter),
-        )
+    fn read_u16(&self, address: usize) -> Result<u16, MachineError> {
+        self.check_ram_range(address, 2)?;
+        Ok(((self.ram[address] as u16) << 8) | self.ram[address + 1] as u16)
+    }
+
+    pub fn decode_next_instruction(&self) -> Result<Instruction, MachineError> {
+        Ok(decode(self.read_u16(self.program_counter)?, self.platform))
+    }
+
+    /// Same as `decode_next_instruction`, going through the decode cache
+    fn fetch_next_instruction(&mut self) -> Result<Instruction, MachineError> {
+        if let Some(Some(instruction)) = self.decode_cache.get(self.program_counter) {
+            return Ok(*instruction);
+        }
+        let instruction = self.decode_next_instruction()?;
+        if let Some(entry) = self.decode_cache.get_mut(self.program_counter) {
+            *entry = Some(instruction);
+        }
+        Ok(instruction)
+    }
+
+    /// Write a byte in RAM, e.g. from a debugger. Writing to `ram` directly
+    /// after some code ran requires a call to `invalidate_decode_cache`
+    pub fn poke(&mut self, address: usize, value: u8) -> Result<(), MachineError> {
+        self.check_ram_range(address, 1)?;
+        self.ram[address] = value;
+        self.invalidate_decode_cache_range(address, 1);
+        Ok(())
+    }
+
+    /// Forget all decoded instructions, to be called after modifying `ram`
+    pub fn invalidate_decode_cache(&mut self) {
+        self.decode_cache.fill(None);
+    }
+
+    /// Forget the decoded instructions overlapping with the `len` bytes
+    /// starting at `address`, which includes the one starting the byte before
+    fn invalidate_decode_cache_range(&mut self, address: usize, len: usize) {
+        let end = (address + len).min(self.decode_cache.len());
+        let start = address.saturating_sub(1).min(end);
+        self.decode_cache[start..end].fill(None);
+    }
+
+    /// Enable or disable the decode cache, which is enabled by default.
+    /// Mostly useful to measure its impact
+    pub fn set_decode_cache_enabled(&mut self, enabled: bool) {
+        self.decode_cache = if enabled {
+            vec![None; self.ram.len()]
+        } else {
+            vec![]
+        };
+    }
+
+    /// Snapshot everything needed to later resume execution exactly from here
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            version: SAVE_STATE_VERSION,
+            platform: self.platform,
+            rom_hash: self.rom.as_ref().map(|rom| rom.hash_hex()),
+            ram: self.ram.clone(),
+            registers: self.registers,
+            index_register: self.index_register,
+            program_counter: self.program_counter,
+            stack: self.stack.to_vec(),
+            stack_index: self.stack_index,
+            timers: self.timers.save_state(),
+            display: self.display.save_state(),
+            keypad: self.keypad.clone(),
+            waiting_for_key: self.waiting_for_key,
+            rpl_flags: self.rpl_flags,
+            halted: self.halted,
+            rng_seed: self.rng.seed(),
+            rng_state: self.rng.state(),
+            quirks: self.quirks,
+        }
+    }
+
+    /// Restore a state from `save_state`. The loaded ROM, the timers clock
+    /// and whether the decode cache is enabled are kept as they are
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), SaveStateError> {
+        state.validate()?;
+        if state.stack.len() != self.stack.len() {
+            return Err(SaveStateError::Invalid(format!(
+                "stack of {} entries, expected {}",
+                state.stack.len(),
+                self.stack.len()
+            )));
+        }
+        self.platform = state.platform;
+        self.ram.clone_from(&state.ram);
+        self.registers = state.registers;
+        self.index_register = state.index_register;
+        self.program_counter = state.program_counter;
+        self.stack.copy_from_slice(&state.stack);
+        self.stack_index = state.stack_index;
+        self.timers.load_state(&state.timers);
+        self.display.load_state(&state.display);
+        self.keypad.clone_from(&state.keypad);
+        self.waiting_for_key = state.waiting_for_key;
+        self.rpl_flags = state.rpl_flags;
+        self.halted = state.halted;
+        self.rng = MachineRng::from_state(state.rng_seed, state.rng_state);
+        self.quirks = state.quirks;
+        // RAM may have a different size and content
+        let decode_cache_enabled = !self.decode_cache.is_empty();
+        self.set_decode_cache_enabled(decode_cache_enabled);
+        Ok(())
+    }
+
+    /// Record that the instruction reads or writes `len` bytes at I
+    fn record_access(&mut self, read: bool, len: usize) {
+        let range = self.index_register as usize..self.index_register as usize + len;
+        self.memory_access = Some(if read {
+            MemoryAccess::Read(range)
+        } else {
+            MemoryAccess::Write(range)
+        });
+    }
+
+    /// Skip the instruction at the program counter. The XO-CHIP F000 NNNN
+    /// instruction is 4 bytes long and skipped as a whole
+    fn skip_next_instruction(&mut self) {
+        if self.platform == Platform::XoChip && self.read_u16(self.program_counter) == Ok(0xF000) {
+            self.program_counter += 4;
+        } else {
+            self.program_counter += 2;
+        }
+    }
+
+    /// Registers VX to VY, in that order, which may be descending
+    fn register_range(x: u8, y: u8) -> Vec<usize> {
+        if x <= y {
+            (x as usize..=y as usize).collect()
+        } else {
+            (y as usize..=x as usize).rev().collect()
+        }
+    }
+
+    /// The value 8XY6/8XYE shift, see https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#8xy6-and-8xye-shift
+    fn shift_operand(&self, rx: u8, ry: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.registers[ry as usize]
+        } else {
+            self.registers[rx as usize]
+        }
+    }
+
+    fn reset_flag_after_logic(&mut self) {
+        if self.quirks.logic_resets_flag {
+            self.set_flag_register(0);
+        }
     }
 
     fn _execute_subtract(&mut self, rx: u8, v1: u8, v2: u8) {
@@ -234,8 +842,63 @@ impl Machine {
         self.registers[rx as usize] = (Wrapping(v1) - Wrapping(v2)).0;
     }
 
-    pub fn execute_one(&mut self) {
-        let instruction = self.decode_next_instruction();
+    /// The RAM read or written by the last instruction executed, if any
+    pub fn last_memory_access(&self) -> Option<&MemoryAccess> {
+        self.memory_access.as_ref()
+    }
+
+    /// Execute the instruction at the program counter. On error, the program
+    /// counter is left on the faulting instruction, so it's up to the caller
+    /// to decide whether to stop or to move past it
+    pub fn execute_one(&mut self) -> Result<StepOutcome, MachineError> {
+        self.memory_access = None;
+        if self.halted {
+            return Ok(StepOutcome::Exited);
+        }
+        let pc = self.program_counter;
+        let outcome = self
+            .fetch_next_instruction()
+            .and_then(|instruction| self.execute_instruction(instruction));
+        match outcome {
+            Ok(_) => self.timers.tick(),
+            Err(_) => self.program_counter = pc,
+        }
+        outcome
+    }
+
+    /// Run one 60hz frame: execute up to `instructions_per_frame` instructions
+    /// then decrement the timers once, like Octo's "tickrate". This is meant to
+    /// be used with `TimerClock::Frames`, otherwise the timers would also be
+    /// decremented by the clock while executing.
+    /// The frame ends early when waiting for a key or when the program exited.
+    pub fn run_frame(&mut self, instructions_per_frame: u32) -> Result<FrameResult, MachineError> {
+        let generation = self.display.generation();
+        let mut result = FrameResult {
+            instructions_executed: 0,
+            outcome: StepOutcome::Executed,
+            display_changed: false,
+        };
+        while result.instructions_executed < instructions_per_frame {
+            if self.halted {
+                result.outcome = StepOutcome::Exited;
+                break;
+            }
+            result.outcome = self.execute_one()?;
+            result.instructions_executed += 1;
+            if result.outcome != StepOutcome::Executed {
+                break;
+            }
+        }
+        self.timers.decrement();
+        result.display_changed = self.display.generation() != generation;
+        Ok(result)
+    }
+
+    fn execute_instruction(
+        &mut self,
+        instruction: Instruction,
+    ) -> Result<StepOutcome, MachineError> {
+        let pc = self.program_counter;
         self.program_counter += 2;
         match instruction {
             Instruction::Zero => {}
@@ -255,61 +918,89 @@ impl Machine {
             Instruction::SetIndexRegister(val) => {
                 self.index_register = val;
             }
+            // DXY0 draws nothing on CHIP-8
+            Instruction::Display(_, _, 0) if self.platform == Platform::Chip8 => {}
             Instruction::Display(rx, ry, n) => {
-                let x = (self.registers[rx as usize] % 64) as usize;
-                let y = (self.registers[ry as usize] % 32) as usize;
-                self.set_flag_register(0);
-                for i in 0..n as usize {
-                    if y + i >= self.display.height() {
-                        break;
-                    }
-                    let sprite_data = self.ram[self.index_register as usize + i];
-                    for j in 0..8 {
-                        if x + j >= self.display.width() {
-                            break;
-                        }
-                        let sprite_val = (sprite_data >> (7 - j)) & 1;
-                        if sprite_val == 0 {
-                            continue;
-                        }
-                        if self.display.pixel(x + j, y + i) {
-                            self.display.set_pixel(x + j, y + i, false);
-                            self.set_flag_register(1);
-                        } else {
-                            self.display.set_pixel(x + j, y + i, true);
-                        }
+                let x = self.registers[rx as usize] as usize;
+                let y = self.registers[ry as usize] as usize;
+                // DXY0 draws a 16x16 sprite on SUPER-CHIP and XO-CHIP
+                let (width, num_rows, bytes_per_row) = if n == 0 {
+                    (16, 16, 2)
+                } else {
+                    (8, n as usize, 1)
+                };
+                // With XO-CHIP, each selected plane consumes its own sprite data,
+                // stored one after the other
+                let mut sprite_address = self.index_register as usize;
+                let num_planes = self.display.selected_planes().count_ones() as usize;
+                let len = num_planes * num_rows * bytes_per_row;
+                self.check_ram_range(sprite_address, len)?;
+                self.memory_access = Some(MemoryAccess::Read(sprite_address..sprite_address + len));
+                let mut collision = false;
+                for plane in 0..NUM_PLANES {
+                    if (self.display.selected_planes() >> plane) & 1 == 0 {
+                        continue;
                     }
+                    let rows: Vec<u16> = (0..num_rows)
+                        .map(|i| {
+                            let address = sprite_address + i * bytes_per_row;
+                            if bytes_per_row == 2 {
+                                ((self.ram[address] as u16) << 8) | self.ram[address + 1] as u16
+                            } else {
+                                self.ram[address] as u16
+                            }
+                        })
+                        .collect();
+                    collision |= self.display.draw_sprite(
+                        plane,
+                        x,
+                        y,
+                        &rows,
+                        width,
+                        self.quirks.sprite_edges,
+                    );
+                    sprite_address += num_rows * bytes_per_row;
                 }
+                self.set_flag_register(collision as u8);
             }
+            Instruction::ScrollUp(n) => self.display.scroll(0, -(n as isize)),
+            Instruction::ScrollDown(n) => self.display.scroll(0, n as isize),
+            Instruction::ScrollRight => self.display.scroll(4, 0),
+            Instruction::ScrollLeft => self.display.scroll(-4, 0),
+            Instruction::Exit => {
+                self.halted = true;
+                return Ok(StepOutcome::Exited);
+            }
+            Instruction::LowRes => self.display.set_hires(false),
+            Instruction::HighRes => self.display.set_hires(true),
             Instruction::Subroutine(v) => {
-                println!("calling subroutine");
-                self.push_stack(self.program_counter as u16);
+                self.push_stack(self.program_counter as u16)?;
                 self.program_counter = v as usize;
             }
             Instruction::Return => {
-                self.program_counter = self.pop_stack() as usize;
+                self.program_counter = self.pop_stack()? as usize;
             }
-            Instruction::Unknown(bytes, location_int) => {
-                println!("Unknown instruction {:#02x?} at {:?}", bytes, location_int)
+            Instruction::Unknown(opcode) => {
+                return Err(MachineError::UnknownOpcode { pc, opcode });
             }
             Instruction::SkipIfEqualRegVal(reg, val) => {
                 if self.registers[reg as usize] == val {
-                    self.program_counter += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::SkipIfNotEqualRegVal(reg, val) => {
                 if self.registers[reg as usize] != val {
-                    self.program_counter += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::SkipIfEqualRegReg(reg1, reg2) => {
                 if self.registers[reg1 as usize] == self.registers[reg2 as usize] {
-                    self.program_counter += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::SkipIfNotEqualRegReg(reg1, reg2) => {
                 if self.registers[reg1 as usize] != self.registers[reg2 as usize] {
-                    self.program_counter += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::Set(rx, ry) => {
@@ -317,12 +1008,15 @@ impl Machine {
             }
             Instruction::Or(rx, ry) => {
                 self.registers[rx as usize] |= self.registers[ry as usize];
+                self.reset_flag_after_logic();
             }
             Instruction::And(rx, ry) => {
                 self.registers[rx as usize] &= self.registers[ry as usize];
+                self.reset_flag_after_logic();
             }
             Instruction::Xor(rx, ry) => {
                 self.registers[rx as usize] ^= self.registers[ry as usize];
+                self.reset_flag_after_logic();
             }
             Instruction::Add(rx, ry) => {
                 let v1 = self.registers[rx as usize];
@@ -348,121 +1042,142 @@ impl Machine {
                     self.registers[rx as usize],
                 );
             }
-            Instruction::ShiftLeft(rx, _) => {
-                // TODO: Need a feature flag here because this is ambiguous
-                let v = self.registers[rx as usize];
-                self.set_flag_register((v >> 7) & 1);
+            Instruction::ShiftLeft(rx, ry) => {
+                let v = self.shift_operand(rx, ry);
                 self.registers[rx as usize] = v << 1;
+                self.set_flag_register((v >> 7) & 1);
             }
-            Instruction::ShiftRight(rx, _) => {
-                // TODO: Need a feature flag here because this is ambiguous
-                let v = self.registers[rx as usize];
-                self.set_flag_register(v & 1);
+            Instruction::ShiftRight(rx, ry) => {
+                let v = self.shift_operand(rx, ry);
                 self.registers[rx as usize] = v >> 1;
+                self.set_flag_register(v & 1);
             }
             Instruction::JumpWithOffset(offset) => {
-                // TODO: Ambiguous one, may want a feature flag
-                self.program_counter = offset as usize + self.registers[0] as usize;
+                // https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#bnnn-jump-with-offset
+                let reg = if self.quirks.jump_with_vx {
+                    ((offset & 0x0F00) >> 8) as usize
+                } else {
+                    0
+                };
+                self.program_counter = offset as usize + self.registers[reg] as usize;
             }
             Instruction::Random(rx, v) => {
-                let mut rng = rand::thread_rng();
-                let n1 = rng.gen::<u8>();
+                let n1 = self.rng.gen::<u8>();
                 self.registers[rx as usize] = n1 & v;
             }
             Instruction::SkipIfKeyPressed(vx) => {
-                if self.key_pressed[self.registers[vx as usize] as usize] {
-                    self.program_counter += 2;
+                if self.keypad.is_pressed(self.registers[vx as usize]) {
+                    self.skip_next_instruction();
                 }
             }
             Instruction::SkipIfKeyNotPressed(vx) => {
-                if !self.key_pressed[self.registers[vx as usize] as usize] {
-                    self.program_counter += 2;
+                if !self.keypad.is_pressed(self.registers[vx as usize]) {
+                    self.skip_next_instruction();
                 }
             }
             Instruction::ReadDelayTimer(vx) => self.registers[vx as usize] = self.timers.delay,
             Instruction::SetDelayTimer(vx) => self.timers.delay = self.registers[vx as usize],
             Instruction::SetSoundTimer(vx) => self.timers.sound = self.registers[vx as usize],
             Instruction::AddToIndex(vx) => {
-                self.index_register += self.registers[vx as usize] as u16;
+                self.index_register = (Wrapping(self.index_register)
+                    + Wrapping(self.registers[vx as usize] as u16))
+                .0;
                 // This is a quirk described here https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#fx1e-add-to-index
-                if self.index_register > 0x1000 {
+                if self.quirks.add_to_index_sets_flag && self.index_register > 0x0FFF {
                     self.set_flag_register(1);
                 }
             }
             Instruction::GetKey(vx) => {
-                match self
-                    .key_pressed
-                    .iter()
-                    .enumerate()
-                    .find(|(_key, pressed)| **pressed)
-                {
-                    Some((key, _pressed)) => self.registers[vx as usize] = key as u8,
-                    None => self.program_counter -= 2,
+                // Like on the COSMAC VIP, this blocks until a key is pressed
+                // *and released*
+                if !self.waiting_for_key {
+                    self.waiting_for_key = true;
+                    self.keypad.clear_released();
+                }
+                match self.keypad.take_released() {
+                    Some(key) => {
+                        self.registers[vx as usize] = key;
+                        self.waiting_for_key = false;
+                    }
+                    None => {
+                        self.program_counter -= 2;
+                        return Ok(StepOutcome::WaitingForKey);
+                    }
                 };
             }
             Instruction::FontCharacter(vx) => {
                 self.index_register = self.get_character_address(self.registers[vx as usize] & 0x0F)
             }
+            Instruction::BigFontCharacter(vx) => {
+                self.index_register =
+                    self.get_big_character_address(self.registers[vx as usize] & 0x0F)
+            }
             Instruction::ConvertToDecimal(vx) => {
                 let val = self.registers[vx as usize];
+                self.check_ram_range(self.index_register as usize, 3)?;
+                self.record_access(false, 3);
                 self.ram[self.index_register as usize] = val / 100;
                 self.ram[self.index_register as usize + 1] = (val / 10) % 10;
                 self.ram[self.index_register as usize + 2] = val % 10;
+                self.invalidate_decode_cache_range(self.index_register as usize, 3);
             }
             Instruction::RegistersToMemory(vx) => {
                 // Potentially quirky, see https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#fx55-and-fx65-store-and-load-memory
+                self.check_ram_range(self.index_register as usize, vx as usize + 1)?;
+                self.record_access(false, vx as usize + 1);
                 for i in 0..vx as usize + 1 {
                     self.ram[self.index_register as usize + i] = self.registers[i];
                 }
+                self.invalidate_decode_cache_range(self.index_register as usize, vx as usize + 1);
+                if self.quirks.load_store_increments_index {
+                    self.index_register = self.index_register.wrapping_add(vx as u16 + 1);
+                }
             }
             Instruction::MemoryToRegisters(vx) => {
                 // Potentially quirky, see https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#fx55-and-fx65-store-and-load-memory
+                self.check_ram_range(self.index_register as usize, vx as usize + 1)?;
+                self.record_access(true, vx as usize + 1);
                 for i in 0..vx as usize + 1 {
                     self.registers[i] = self.ram[self.index_register as usize + i];
                 }
+                if self.quirks.load_store_increments_index {
+                    self.index_register = self.index_register.wrapping_add(vx as u16 + 1);
+                }
+            }
+            Instruction::LoadLongIndex => {
+                self.index_register = self.read_u16(self.program_counter)?;
+                self.program_counter += 2;
+            }
+            Instruction::SaveRange(rx, ry) => {
+                self.check_ram_range(self.index_register as usize, rx.abs_diff(ry) as usize + 1)?;
+                self.record_access(false, rx.abs_diff(ry) as usize + 1);
+                for (i, reg) in Machine::register_range(rx, ry).into_iter().enumerate() {
+                    self.ram[self.index_register as usize + i] = self.registers[reg];
+                }
+                self.invalidate_decode_cache_range(
+                    self.index_register as usize,
+                    rx.abs_diff(ry) as usize + 1,
+                );
+            }
+            Instruction::LoadRange(rx, ry) => {
+                self.check_ram_range(self.index_register as usize, rx.abs_diff(ry) as usize + 1)?;
+                self.record_access(true, rx.abs_diff(ry) as usize + 1);
+                for (i, reg) in Machine::register_range(rx, ry).into_iter().enumerate() {
+                    self.registers[reg] = self.ram[self.index_register as usize + i];
+                }
+            }
+            Instruction::SelectPlanes(mask) => self.display.select_planes(mask),
+            Instruction::SaveFlags(vx) => {
+                self.rpl_flags[..=vx as usize].copy_from_slice(&self.registers[..=vx as usize]);
+            }
+            Instruction::LoadFlags(vx) => {
+                self.registers[..=vx as usize].copy_from_slice(&self.rpl_flags[..=vx as usize]);
             }
         }
-        // Reset keypressed
-        self.key_pressed.fill(false);
-        self.timers.tick();
-    }
-}
-
-#[cfg(not(test))]
-pub fn now() -> SystemTime {
-    SystemTime::now()
-}
-
-#[cfg(test)]
-pub mod mock_time {
-    use super::*;
-    use std::cell::RefCell;
-
-    thread_local! {
-        static MOCK_TIME: RefCell<Option<SystemTime>> = RefCell::new(None);
-    }
-
-    pub fn now() -> SystemTime {
-        MOCK_TIME.with(|cell| {
-            cell.borrow()
-                .as_ref()
-                .cloned()
-                .unwrap_or_else(SystemTime::now)
-        })
-    }
-
-    pub fn set_mock_time(time: SystemTime) {
-        MOCK_TIME.with(|cell| *cell.borrow_mut() = Some(time));
-    }
-
-    pub fn clear_mock_time() {
-        MOCK_TIME.with(|cell| *cell.borrow_mut() = None);
+        Ok(StepOutcome::Executed)
     }
 }
 
-#[cfg(test)]
-pub use mock_time::now;
-
 /// https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#font
 const FONT: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -483,16 +1198,66 @@ const FONT: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+/// SUPER-CHIP 8x10 digits, as well as the A-F extension from Octo
+const BIG_FONT: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[cfg(test)]
+impl Machine {
+    /// Assemble Octo source, see `crate::assembler`
+    pub(crate) fn from_octo(platform: Platform, source: &str) -> Machine {
+        let mut machine = Machine::new(platform);
+        let program = crate::assembler::assemble(source).unwrap();
+        machine.load_rom_from_bytes(&program.bytes).unwrap();
+        machine
+    }
+}
+
+/// Counts V0 up to 10, saving it to `buffer` and drawing it as a sprite
+#[cfg(test)]
+pub(crate) const COUNTING_LOOP: &str = "
+    : main
+        v0 := 3
+        i := buffer
+    : loop
+        v0 += 1
+        save v0
+        sprite v0 v0 1
+        if v0 != 10 then jump loop
+        exit
+    : buffer
+    ";
+
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
 
     use super::*;
 
     impl Machine {
         fn from_instrhex(data: &[u16]) -> Machine {
-            let mut machine = Machine::default();
-            machine.load_rom_from_instrhex(data);
+            Machine::from_instrhex_on(Platform::Chip8, data)
+        }
+
+        fn from_instrhex_on(platform: Platform, data: &[u16]) -> Machine {
+            let mut machine = Machine::new(platform);
+            machine.load_rom_from_instrhex(data).unwrap();
             machine
         }
     }
@@ -501,7 +1266,7 @@ mod tests {
     fn test_instr_clear_screen() {
         let mut machine = Machine::from_instrhex(&[0x00E0]);
         machine.display.set_pixel(5, 7, true);
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert!(!machine.display.pixel(5, 7));
     }
 
@@ -509,14 +1274,14 @@ mod tests {
     fn test_instr_jump() {
         let mut machine = Machine::from_instrhex(&[0x1ABC]);
         assert_eq!(machine.program_counter, 0x200);
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.program_counter, 0xABC);
     }
 
     #[test]
     fn test_instr_set_reg_to_val() {
         let mut machine = Machine::from_instrhex(&[0x6CDE]);
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[0xC], 0xDE);
     }
 
@@ -526,7 +1291,7 @@ mod tests {
         // Test that overflow cycles and doesn't set the VF register
         // https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#7xnn-add
         machine.registers[0] = 0xFF;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[0], 0x31);
         assert_eq!(machine.flag_register(), 0);
     }
@@ -534,7 +1299,7 @@ mod tests {
     #[test]
     fn test_instr_set_index_register() {
         let mut machine = Machine::from_instrhex(&[0xABCD]);
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.index_register, 0xBCD);
     }
 
@@ -553,7 +1318,7 @@ mod tests {
         assert_eq!(machine.display.pixels().count_value(true), 0);
 
         // ==== First execution should show the sprite
-        machine.execute_one();
+        machine.execute_one().unwrap();
         // First row
         assert!(machine.display.pixel(10, 5));
         assert!(machine.display.pixel(11, 5));
@@ -568,7 +1333,7 @@ mod tests {
         assert_eq!(machine.display.pixels().count_value(true), 12);
 
         // ==== Executing a second time should erase it
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.display.pixels().count_value(true), 0);
     }
 
@@ -583,7 +1348,7 @@ mod tests {
             0x00EE, // return
         ]);
         for _ in 0..5 {
-            machine.execute_one();
+            machine.execute_one().unwrap();
         }
         assert_eq!(machine.registers[0], 1);
         // We should have poped from the stack
@@ -600,7 +1365,7 @@ mod tests {
         ]);
         machine.registers[2] = 0x10;
         for _ in 0..4 {
-            machine.execute_one();
+            machine.execute_one().unwrap();
         }
         assert_eq!(machine.registers[1], 0xFF);
     }
@@ -615,7 +1380,7 @@ mod tests {
         ]);
         machine.registers[2] = 0x10;
         for _ in 0..4 {
-            machine.execute_one();
+            machine.execute_one().unwrap();
         }
         assert_eq!(machine.registers[1], 0xFF);
     }
@@ -632,7 +1397,7 @@ mod tests {
         machine.registers[2] = 0x11;
         machine.registers[3] = 0x0;
         for _ in 0..4 {
-            machine.execute_one();
+            machine.execute_one().unwrap();
         }
         assert_eq!(machine.registers[0], 0xFF);
     }
@@ -649,7 +1414,7 @@ mod tests {
         machine.registers[2] = 0x11;
         machine.registers[3] = 0x0;
         for _ in 0..4 {
-            machine.execute_one();
+            machine.execute_one().unwrap();
         }
         assert_eq!(machine.registers[0], 0xFF);
     }
@@ -659,7 +1424,7 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8120]);
         machine.registers[1] = 0x42;
         machine.registers[2] = 0x43;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0x43);
         assert_eq!(machine.registers[2], 0x43);
     }
@@ -669,7 +1434,7 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8121]);
         machine.registers[1] = 0b0101;
         machine.registers[2] = 0b0011;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b0111);
     }
 
@@ -678,7 +1443,7 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8122]);
         machine.registers[1] = 0b0101;
         machine.registers[2] = 0b0011;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b0001);
     }
 
@@ -687,7 +1452,7 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8123]);
         machine.registers[1] = 0b0101;
         machine.registers[2] = 0b0011;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b0110);
     }
 
@@ -696,11 +1461,11 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8124, 0x8124]);
         machine.registers[1] = 250;
         machine.registers[2] = 5;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 255);
         assert_eq!(machine.flag_register(), 0);
 
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 4);
         assert_eq!(machine.flag_register(), 1);
     }
@@ -710,15 +1475,15 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8125, 0x8125, 0x8125]);
         machine.registers[1] = 10;
         machine.registers[2] = 5;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 5);
         assert_eq!(machine.flag_register(), 1);
 
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0);
         assert_eq!(machine.flag_register(), 1);
 
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 251);
         assert_eq!(machine.flag_register(), 0);
     }
@@ -728,12 +1493,12 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0x8127, 0x8127, 0x8127]);
         machine.registers[1] = 5;
         machine.registers[2] = 10;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 5);
         assert_eq!(machine.flag_register(), 1);
 
         machine.registers[2] = 4;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 255);
         assert_eq!(machine.flag_register(), 0);
     }
@@ -742,11 +1507,11 @@ mod tests {
     fn test_instr_shift_right() {
         let mut machine = Machine::from_instrhex(&[0x8126, 0x8126]);
         machine.registers[1] = 0b0101;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b010);
         assert_eq!(machine.flag_register(), 1);
 
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b01);
         assert_eq!(machine.flag_register(), 0);
     }
@@ -755,48 +1520,116 @@ mod tests {
     fn test_instr_shift_left() {
         let mut machine = Machine::from_instrhex(&[0x812E, 0x812E]);
         machine.registers[1] = 0b10101010;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b01010100);
         assert_eq!(machine.flag_register(), 1);
 
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[1], 0b10101000);
         assert_eq!(machine.flag_register(), 0);
     }
 
+    #[test]
+    fn test_instr_logic_resets_flag_quirk() {
+        for opcode in [0x8121, 0x8122, 0x8123] {
+            let mut machine = Machine::from_instrhex(&[opcode]);
+            machine.quirks.logic_resets_flag = true;
+            machine.registers[0xF] = 1;
+            machine.execute_one().unwrap();
+            assert_eq!(machine.flag_register(), 0);
+
+            let mut machine = Machine::from_instrhex(&[opcode]);
+            machine.registers[0xF] = 1;
+            machine.execute_one().unwrap();
+            assert_eq!(machine.flag_register(), 1);
+        }
+    }
+
+    #[test]
+    fn test_instr_shift_uses_vy_quirk() {
+        let mut machine = Machine::from_instrhex(&[0x8126, 0x812E]);
+        machine.quirks = Quirks::cosmac_vip();
+        machine.registers[1] = 0xFF;
+        machine.registers[2] = 0b0110;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[1], 0b011);
+        assert_eq!(machine.flag_register(), 0);
+
+        machine.registers[2] = 0b10000001;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[1], 0b10);
+        assert_eq!(machine.flag_register(), 1);
+    }
+
     #[test]
     fn test_instr_jump_with_offset() {
         let mut machine = Machine::from_instrhex(&[0xB012]);
         machine.registers[0] = 5;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.program_counter, 0x012 + 5);
     }
 
+    #[test]
+    fn test_instr_jump_with_offset_vx_quirk() {
+        let mut machine = Machine::from_instrhex(&[0xB312]);
+        machine.quirks = Quirks::superchip();
+        machine.registers[0] = 5;
+        machine.registers[3] = 7;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.program_counter, 0x312 + 7);
+    }
+
     #[test]
     fn test_instr_random() {
-        // We can't easily seed the rng, so what we do is generate a few random numbers and check
-        // they were anded with 0x0F
+        // Generate a few random numbers and check they were anded with 0x0F
         let mut machine = Machine::from_instrhex(&[
             0xC00F,                            // generate random number,
             0x1000 + ROM_START_ADDRESS as u16, // infinite loop to rng above
         ]);
         for _ in 0..10 {
-            machine.execute_one();
+            machine.execute_one().unwrap();
             assert!(machine.registers[0] < 0x10);
         }
     }
 
+    #[test]
+    fn test_instr_random_seeded() {
+        let run = |machine: &mut Machine| -> Vec<u8> {
+            machine
+                .load_rom_from_instrhex(&[0xC0FF, 0x1000 + ROM_START_ADDRESS as u16])
+                .unwrap();
+            (0..20)
+                .map(|_| {
+                    machine.execute_one().unwrap();
+                    machine.execute_one().unwrap();
+                    machine.registers[0]
+                })
+                .collect()
+        };
+        let mut machine1 = Machine::with_seed(Platform::Chip8, 42);
+        let mut machine2 = Machine::with_seed(Platform::Chip8, 42);
+        let values = run(&mut machine1);
+        assert_eq!(values, run(&mut machine2));
+        assert_ne!(values, run(&mut Machine::with_seed(Platform::Chip8, 43)));
+
+        // Restoring the state resumes the same sequence
+        let mut machine3 = Machine::with_seed(Platform::Chip8, 0);
+        machine3.rng = MachineRng::from_state(machine1.rng.seed(), machine1.rng.state());
+        assert_eq!(run(&mut machine1), run(&mut machine3));
+        assert_eq!(machine3.rng.seed(), 42);
+    }
+
     #[test]
     fn test_instr_skip_if_pressed() {
         let mut machine = Machine::from_instrhex(&[0xE29E, 0x1FFF, 0x6001]);
         machine.registers[2] = 5;
-        machine.key_pressed[5] = true;
-        machine.execute_one();
-        // Check keypressed are reset after each instruction
-        assert_eq!(machine.key_pressed[5], false);
+        machine.keypad.press(5);
+        machine.execute_one().unwrap();
+        // Keys stay held until they are released
+        assert!(machine.keypad.is_pressed(5));
 
         // We should have jumped to the set (0x6)
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[0], 1);
     }
 
@@ -804,10 +1637,9 @@ mod tests {
     fn test_instr_skip_if_not_pressed() {
         let mut machine = Machine::from_instrhex(&[0xE2A1, 0x1FFF, 0x6001]);
         machine.registers[2] = 5;
-        machine.key_pressed[5] = false;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         // We should have jumped to the set (0x6)
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[0], 1);
     }
 
@@ -815,7 +1647,7 @@ mod tests {
     fn test_instr_read_delay_timer() {
         let mut machine = Machine::from_instrhex(&[0xF007]);
         machine.timers.delay = 234;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[0], 234);
     }
 
@@ -823,7 +1655,7 @@ mod tests {
     fn test_instr_set_delay_timer() {
         let mut machine = Machine::from_instrhex(&[0xF015]);
         machine.registers[0] = 234;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.timers.sound, 0);
         assert_eq!(machine.timers.delay, 234);
     }
@@ -832,41 +1664,91 @@ mod tests {
     fn test_instr_set_sound_timer() {
         let mut machine = Machine::from_instrhex(&[0xF018]);
         machine.registers[0] = 233;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.timers.sound, 233);
         assert_eq!(machine.timers.delay, 0);
     }
 
     #[test]
     fn test_timers_decrement() {
-        mock_time::set_mock_time(SystemTime::UNIX_EPOCH);
+        let time = Arc::new(Mutex::new(Duration::ZERO));
+        let source_time = time.clone();
         let mut machine = Machine::from_instrhex(&[
             0x1000 + ROM_START_ADDRESS as u16, // an infinite loop to self
         ]);
+        machine.timers.set_clock(TimerClock::Time(Box::new(move || {
+            *source_time.lock().unwrap()
+        })));
         machine.timers.sound = 240;
         machine.timers.delay = 120;
         // We move time by 2 seconds but do it in steps that are not all dividable by 60 to test the `last_tick_remainder` behavior
-        mock_time::set_mock_time(SystemTime::UNIX_EPOCH + Duration::from_millis(850));
-        machine.execute_one();
-        mock_time::set_mock_time(SystemTime::UNIX_EPOCH + Duration::from_millis(900));
-        machine.execute_one();
-        mock_time::set_mock_time(SystemTime::UNIX_EPOCH + Duration::from_millis(1232));
-        machine.execute_one();
-        mock_time::set_mock_time(SystemTime::UNIX_EPOCH + Duration::from_millis(2000));
-        machine.execute_one();
+        for ms in [850, 900, 1232, 2000] {
+            *time.lock().unwrap() = Duration::from_millis(ms);
+            machine.execute_one().unwrap();
+        }
         assert_eq!(machine.timers.sound, 120);
         assert_eq!(machine.timers.delay, 0);
     }
 
+    #[test]
+    fn test_timers_decrement_cycles() {
+        let mut machine = Machine::from_instrhex(&[
+            0x1000 + ROM_START_ADDRESS as u16, // an infinite loop to self
+        ]);
+        machine.timers.set_clock(TimerClock::Cycles {
+            instructions_per_tick: 10,
+        });
+        machine.timers.delay = 10;
+        for _ in 0..35 {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.timers.delay, 7);
+    }
+
+    #[test]
+    fn test_timers_decrement_frames() {
+        let mut machine = Machine::from_instrhex(&[
+            0x1000 + ROM_START_ADDRESS as u16, // an infinite loop to self
+        ]);
+        machine.timers.set_clock(TimerClock::Frames);
+        machine.timers.delay = 2;
+        machine.timers.sound = 1;
+        for _ in 0..100 {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.timers.delay, 2);
+        machine.timers.decrement();
+        machine.timers.decrement();
+        assert_eq!(machine.timers.delay, 0);
+        assert_eq!(machine.timers.sound, 0);
+    }
+
     #[test]
     fn test_instr_add_to_index() {
         let mut machine = Machine::from_instrhex(&[0xF31E]);
         machine.index_register = 67;
         machine.registers[3] = 233;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.index_register, 300);
     }
 
+    #[test]
+    fn test_instr_add_to_index_overflow_quirk() {
+        let mut machine = Machine::from_instrhex(&[0xF31E]);
+        machine.index_register = 0xFFE;
+        machine.registers[3] = 2;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.index_register, 0x1000);
+        assert_eq!(machine.flag_register(), 1);
+
+        let mut machine = Machine::from_instrhex(&[0xF31E]);
+        machine.quirks.add_to_index_sets_flag = false;
+        machine.index_register = 0xFFE;
+        machine.registers[3] = 2;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.flag_register(), 0);
+    }
+
     #[test]
     fn test_instr_get_key() {
         let mut machine = Machine::from_instrhex(&[
@@ -874,12 +1756,16 @@ mod tests {
             0x1000 + ROM_START_ADDRESS as u16 + 2, // infinite loop
         ]);
         // No key pressed => should just loop to self
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[2], 0);
         assert_eq!(machine.program_counter, ROM_START_ADDRESS);
-        // Key pressed => should store in register and move on
-        machine.key_pressed[4] = true;
-        machine.execute_one();
+        // Key pressed => should still wait for the release
+        machine.keypad.press(4);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS);
+        // Key released => should store in register and move on
+        machine.keypad.release(4);
+        machine.execute_one().unwrap();
         assert_eq!(machine.program_counter, ROM_START_ADDRESS + 2);
         assert_eq!(machine.registers[2], 4);
     }
@@ -888,8 +1774,9 @@ mod tests {
     fn test_instr_font_character() {
         let mut machine = Machine::from_instrhex(&[0xF129]);
         machine.registers[1] = 0xE;
-        machine.execute_one();
-        assert_eq!(machine.index_register, 0x50 + 14 * 4);
+        machine.execute_one().unwrap();
+        // Each character of the font is 5 bytes
+        assert_eq!(machine.index_register, 0x50 + 14 * 5);
     }
 
     #[test]
@@ -897,7 +1784,7 @@ mod tests {
         let mut machine = Machine::from_instrhex(&[0xF233]);
         machine.registers[2] = 156;
         machine.index_register = 42;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.ram[42], 1);
         assert_eq!(machine.ram[43], 5);
         assert_eq!(machine.ram[44], 6);
@@ -912,7 +1799,7 @@ mod tests {
         machine.registers[3] = 179;
         machine.registers[4] = 180; // should be ignored
         machine.index_register = 10;
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.ram[10], 42);
         assert_eq!(machine.ram[11], 45);
         assert_eq!(machine.ram[12], 153);
@@ -929,10 +1816,577 @@ mod tests {
         machine.ram[22] = 134;
         machine.ram[23] = 144; // should be ignored
 
-        machine.execute_one();
+        machine.execute_one().unwrap();
         assert_eq!(machine.registers[0], 42);
         assert_eq!(machine.registers[1], 43);
         assert_eq!(machine.registers[2], 134);
         assert_eq!(machine.registers[3], 0);
     }
+
+    #[test]
+    fn test_instr_load_store_increments_index_quirk() {
+        let mut machine = Machine::from_instrhex(&[0xF255, 0xF165]);
+        machine.quirks = Quirks::cosmac_vip();
+        machine.registers[0] = 1;
+        machine.registers[1] = 2;
+        machine.registers[2] = 3;
+        machine.index_register = 10;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.index_register, 13);
+
+        machine.ram[13] = 42;
+        machine.ram[14] = 43;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[0], 42);
+        assert_eq!(machine.registers[1], 43);
+        assert_eq!(machine.index_register, 15);
+    }
+
+    #[test]
+    fn test_instr_load_store_at_top_of_memory() {
+        // The XO-CHIP quirks increment I, which wraps around past the 64KiB
+        let mut machine = Machine::new(Platform::XoChip);
+        machine.load_rom_from_instrhex(&[0xFF55, 0xFF65]).unwrap();
+        machine.index_register = 0xFFF0;
+        machine.registers[15] = 7;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.ram[0xFFFF], 7);
+        assert_eq!(machine.index_register, 0);
+
+        machine.index_register = 0xFFF0;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[15], 7);
+        assert_eq!(machine.index_register, 0);
+    }
+
+    #[test]
+    fn test_instr_hires_lores() {
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0x00FF, 0x00FE]);
+        machine.display.set_pixel(5, 7, true);
+        machine.execute_one().unwrap();
+        assert!(machine.display.is_hires());
+        assert_eq!(machine.display.width(), 128);
+        assert_eq!(machine.display.height(), 64);
+        assert_eq!(machine.display.pixels().count_value(true), 0);
+
+        machine.execute_one().unwrap();
+        assert!(!machine.display.is_hires());
+        assert_eq!(machine.display.width(), 64);
+        assert_eq!(machine.display.height(), 32);
+    }
+
+    #[test]
+    fn test_instr_scroll() {
+        let mut machine =
+            Machine::from_instrhex_on(Platform::SuperChip, &[0x00C3, 0x00FB, 0x00FC, 0x00FC]);
+        machine.display.set_pixel(10, 5, true);
+        machine.display.set_pixel(2, 31, true);
+        machine.execute_one().unwrap();
+        assert!(machine.display.pixel(10, 8));
+        // The pixel scrolled off the bottom is lost
+        assert_eq!(machine.display.pixels().count_value(true), 1);
+
+        machine.execute_one().unwrap();
+        assert!(machine.display.pixel(14, 8));
+        machine.execute_one().unwrap();
+        machine.execute_one().unwrap();
+        assert!(machine.display.pixel(6, 8));
+        assert_eq!(machine.display.pixels().count_value(true), 1);
+    }
+
+    #[test]
+    fn test_instr_exit() {
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0x00FD, 0x6001]);
+        machine.execute_one().unwrap();
+        assert!(machine.halted);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[0], 0);
+    }
+
+    #[test]
+    fn test_extensions_unknown_on_chip8() {
+        for opcode in [0x00FF, 0x5122, 0xF000] {
+            let mut machine = Machine::from_instrhex(&[opcode, 0x1234]);
+            assert_eq!(
+                machine.execute_one(),
+                Err(MachineError::UnknownOpcode {
+                    pc: ROM_START_ADDRESS,
+                    opcode
+                })
+            );
+        }
+        // Only XO-CHIP skips F000 NNNN as a whole
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0x3000, 0xF000]);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS + 4);
+    }
+
+    #[test]
+    fn test_instr_display_16x16() {
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0x00FF, 0xD120, 0xD120]);
+        machine.registers[1] = 100;
+        machine.registers[2] = 40;
+        machine.index_register = 0x400;
+        machine.ram[0x400] = 0b10000000;
+        machine.ram[0x401] = 0b00000001;
+        machine.ram[0x400 + 30] = 0xFF;
+        machine.ram[0x400 + 31] = 0xFF;
+        machine.execute_one().unwrap();
+        machine.execute_one().unwrap();
+        assert!(machine.display.pixel(100, 40));
+        assert!(!machine.display.pixel(101, 40));
+        assert!(machine.display.pixel(115, 40));
+        assert!(machine.display.pixel(107, 55));
+        assert_eq!(machine.display.pixels().count_value(true), 18);
+        assert_eq!(machine.flag_register(), 0);
+
+        machine.execute_one().unwrap();
+        assert_eq!(machine.display.pixels().count_value(true), 0);
+        assert_eq!(machine.flag_register(), 1);
+    }
+
+    #[test]
+    fn test_instr_display_zero_rows_on_chip8() {
+        let mut machine = Machine::from_instrhex(&[0xD120]);
+        machine.index_register = 0x400;
+        machine.ram[0x400..0x420].fill(0xFF);
+        machine.registers[15] = 7;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.display.pixels().count_value(true), 0);
+        assert_eq!(machine.flag_register(), 7);
+        assert_eq!(machine.last_memory_access(), None);
+    }
+
+    #[test]
+    fn test_instr_big_font_character() {
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0xF130]);
+        machine.registers[1] = 0x3;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.index_register, 0xA0 + 3 * 10);
+        assert_eq!(machine.ram[machine.index_register as usize], BIG_FONT[30]);
+    }
+
+    #[test]
+    fn test_instr_save_load_flags() {
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0xF275, 0xF385]);
+        machine.registers[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.rpl_flags[0..4], [1, 2, 3, 0]);
+
+        machine.registers = [0xFF; 16];
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[0..5], [1, 2, 3, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_xo_chip_ram_size() {
+        assert_eq!(Machine::default().ram.len(), 4096);
+        assert_eq!(Machine::new(Platform::XoChip).ram.len(), 65536);
+    }
+
+    #[test]
+    fn test_platform_quirks() {
+        assert_eq!(Machine::default().quirks, Quirks::default());
+        assert_eq!(
+            Machine::new(Platform::XoChip).quirks.sprite_edges,
+            SpriteEdges::Wrap
+        );
+    }
+
+    #[test]
+    fn test_instr_load_long_index() {
+        let mut machine = Machine::new(Platform::XoChip);
+        machine
+            .load_rom_from_instrhex(&[0xF000, 0xBEEF, 0x6001])
+            .unwrap();
+        machine.execute_one().unwrap();
+        assert_eq!(machine.index_register, 0xBEEF);
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS + 4);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[0], 1);
+    }
+
+    #[test]
+    fn test_skip_over_long_index() {
+        let mut machine = Machine::new(Platform::XoChip);
+        machine
+            .load_rom_from_instrhex(&[0x3000, 0xF000, 0xBEEF, 0x6001])
+            .unwrap();
+        machine.execute_one().unwrap();
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS + 6);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[0], 1);
+        assert_eq!(machine.index_register, 0);
+    }
+
+    #[test]
+    fn test_instr_save_load_range() {
+        let mut machine = Machine::from_instrhex_on(Platform::XoChip, &[0x5242, 0x5422, 0x5A83]);
+        machine.registers[2..5].copy_from_slice(&[1, 2, 3]);
+        machine.index_register = 0x400;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.ram[0x400..0x404], [1, 2, 3, 0]);
+        assert_eq!(machine.index_register, 0x400);
+
+        // Descending range
+        machine.execute_one().unwrap();
+        assert_eq!(machine.ram[0x400..0x403], [3, 2, 1]);
+
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[8..11], [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_instr_display_planes() {
+        let mut machine =
+            Machine::from_instrhex_on(Platform::XoChip, &[0xF201, 0xD011, 0xF301, 0xD011]);
+        machine.index_register = 0x400;
+        machine.ram[0x400] = 0b11000000;
+        machine.ram[0x401] = 0b10000000;
+
+        // Plane 2 only
+        machine.execute_one().unwrap();
+        machine.execute_one().unwrap();
+        assert_eq!(machine.display.pixels().count_value(true), 0);
+        assert_eq!(machine.display.plane(1).count_value(true), 2);
+        assert_eq!(machine.display.color_index(0, 0), 2);
+
+        // Both planes, each one using its own sprite data
+        machine.execute_one().unwrap();
+        machine.execute_one().unwrap();
+        assert_eq!(machine.display.color_index(0, 0), 1);
+        assert_eq!(machine.display.color_index(1, 0), 3);
+        assert_eq!(machine.flag_register(), 1);
+        let image = machine.display.to_image();
+        assert_eq!(image.data()[4..8], DEFAULT_PALETTE[3]);
+    }
+
+    #[test]
+    fn test_display_dirty_rows() {
+        let mut display = Display::default();
+        display.rgba();
+        assert_eq!(display.dirty_rows(), 0);
+        let generation = display.generation();
+
+        display.draw_sprite(0, 2, 3, &[0b1, 0b0, 0b1], 1, SpriteEdges::Clip);
+        assert_eq!(display.dirty_rows(), (1 << 3) | (1 << 5));
+        assert_eq!(display.generation(), generation + 1);
+
+        // Only the dirty rows are converted, and the result is the same as a
+        // full conversion
+        let offset = (5 * display.width() + 2) * 4;
+        assert_eq!(display.rgba()[offset..offset + 4], DEFAULT_PALETTE[1]);
+        assert_eq!(display.dirty_rows(), 0);
+
+        // Setting a pixel to its current value is not a change
+        display.set_pixel(2, 5, true);
+        assert_eq!(display.dirty_rows(), 0);
+        assert_eq!(display.generation(), generation + 1);
+
+        display.set_palette([[1, 2, 3, 4]; 4]);
+        assert_eq!(display.dirty_rows(), u64::MAX);
+        assert_eq!(display.rgba()[0..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_instr_clear_and_scroll_selected_planes() {
+        let mut machine = Machine::from_instrhex_on(Platform::XoChip, &[0xF201, 0x00D2, 0x00E0]);
+        machine.display.set_pixel(3, 3, true);
+        machine.display.select_planes(2);
+        machine
+            .display
+            .draw_sprite(1, 5, 5, &[1], 1, SpriteEdges::Clip);
+        machine.display.select_planes(1);
+
+        machine.execute_one().unwrap();
+        machine.execute_one().unwrap();
+        assert!(machine.display.pixel(3, 3));
+        assert!(machine.display.plane(1)[(3, 5)]);
+
+        machine.execute_one().unwrap();
+        assert!(machine.display.pixel(3, 3));
+        assert_eq!(machine.display.plane(1).count_value(true), 0);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut machine = Machine::from_instrhex(&[
+            0x2000 + ROM_START_ADDRESS as u16, // recursive call to self
+        ]);
+        for _ in 0..machine.stack.len() {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.execute_one(), Err(MachineError::StackOverflow));
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS);
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut machine = Machine::from_instrhex(&[0x00EE]);
+        assert_eq!(machine.execute_one(), Err(MachineError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut machine = Machine::from_instrhex(&[0x6001, 0x0123]);
+        machine.execute_one().unwrap();
+        assert_eq!(
+            machine.execute_one(),
+            Err(MachineError::UnknownOpcode {
+                pc: ROM_START_ADDRESS + 2,
+                opcode: 0x0123
+            })
+        );
+        // The program counter stays on the faulting instruction
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS + 2);
+        assert_eq!(
+            machine.decode_next_instruction(),
+            Ok(Instruction::Unknown(0x0123))
+        );
+    }
+
+    #[test]
+    fn test_decode_cache_invalidated_by_writes() {
+        // The jump at 0x208 is executed once, then overwritten by FX55 to
+        // become a jump to 0x212
+        let mut machine = Machine::from_instrhex(&[0x6012, 0xA209, 0x1208, 0xF055, 0x1206]);
+        for _ in 0..5 {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.program_counter, ROM_START_ADDRESS + 8);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.program_counter, 0x212);
+
+        // Same with an external write
+        machine.program_counter = ROM_START_ADDRESS;
+        machine.execute_one().unwrap();
+        machine.poke(ROM_START_ADDRESS + 1, 0x34).unwrap();
+        machine.program_counter = ROM_START_ADDRESS;
+        machine.execute_one().unwrap();
+        assert_eq!(machine.registers[0], 0x34);
+        assert_eq!(
+            machine.poke(0x1000, 0),
+            Err(MachineError::MemoryOutOfBounds { addr: 0x1000 })
+        );
+    }
+
+    #[test]
+    fn test_last_memory_access() {
+        let mut machine = Machine::from_instrhex(&[0xA300, 0xF255, 0xF165, 0xD013, 0xF033]);
+        machine.execute_one().unwrap();
+        assert_eq!(machine.last_memory_access(), None);
+        machine.execute_one().unwrap();
+        assert_eq!(
+            machine.last_memory_access(),
+            Some(&MemoryAccess::Write(0x300..0x303))
+        );
+        machine.execute_one().unwrap();
+        assert_eq!(
+            machine.last_memory_access(),
+            Some(&MemoryAccess::Read(0x300..0x302))
+        );
+        machine.execute_one().unwrap();
+        assert_eq!(
+            machine.last_memory_access(),
+            Some(&MemoryAccess::Read(0x300..0x303))
+        );
+        machine.execute_one().unwrap();
+        assert_eq!(
+            machine.last_memory_access(),
+            Some(&MemoryAccess::Write(0x300..0x303))
+        );
+    }
+
+    #[test]
+    fn test_octo_program() {
+        let mut machine = Machine::from_octo(
+            Platform::SuperChip,
+            "
+            :alias counter v0
+            : main
+                loop
+                    while counter != 10
+                    counter += 1
+                    increment-total
+                again
+                exit
+            : increment-total
+                v1 += counter
+                return
+            ",
+        );
+        while machine.execute_one().unwrap() != StepOutcome::Exited {}
+        assert_eq!(machine.registers[0], 10);
+        assert_eq!(machine.registers[1], 55);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        for opcode in [0xF033, 0xF255, 0xF265, 0xD015] {
+            let mut machine = Machine::from_instrhex(&[opcode]);
+            machine.index_register = 0xFFE;
+            assert_eq!(
+                machine.execute_one(),
+                Err(MachineError::MemoryOutOfBounds { addr: 0x1000 }),
+                "opcode {:#06x}",
+                opcode
+            );
+        }
+
+        // Fetching past the end of the RAM
+        let mut machine = Machine::from_instrhex(&[0x1FFF]);
+        machine.execute_one().unwrap();
+        assert_eq!(
+            machine.execute_one(),
+            Err(MachineError::MemoryOutOfBounds { addr: 0x1000 })
+        );
+    }
+
+    #[test]
+    fn test_step_outcomes() {
+        let mut machine = Machine::from_instrhex(&[0xF00A]);
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::WaitingForKey));
+        machine.keypad.press(3);
+        machine.keypad.release(3);
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::Executed));
+
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0x00FD]);
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::Exited));
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::Exited));
+    }
+
+    #[test]
+    fn test_load_rom() {
+        let mut machine = Machine::default();
+        machine.load_rom_from_bytes(&[0x60, 0x01]).unwrap();
+        let rom = machine.rom.as_ref().unwrap();
+        assert_eq!(rom.size(), 2);
+        assert_eq!(rom.hash_hex(), "50d705af141eba1f6b6b3e92580d730d7c5f62f9");
+        assert_eq!(machine.ram[ROM_START_ADDRESS], 0x60);
+    }
+
+    #[test]
+    fn test_load_rom_errors() {
+        let mut machine = Machine::default();
+        assert!(matches!(
+            machine.load_rom_from_bytes(&[]),
+            Err(RomLoadError::Empty)
+        ));
+        assert!(matches!(
+            machine.load_rom_from_bytes(&[0; 3585]),
+            Err(RomLoadError::TooLarge {
+                size: 3585,
+                max_size: 3584
+            })
+        ));
+        assert!(matches!(
+            machine.load_rom_from_file("roms/does_not_exist.ch8"),
+            Err(RomLoadError::Io(_))
+        ));
+        assert!(machine.rom.is_none());
+
+        // XO-CHIP has a lot more room
+        let mut machine = Machine::new(Platform::XoChip);
+        machine.load_rom_from_bytes(&[0; 3585]).unwrap();
+    }
+
+    #[test]
+    fn test_instr_skip_if_pressed_while_held() {
+        let mut machine = Machine::from_instrhex(&[
+            0xE09E,                            // skip if key in v0 is pressed
+            0x1000 + ROM_START_ADDRESS as u16, // loop while it isn't
+            0x7101,                            // count iterations while it is
+            0x1000 + ROM_START_ADDRESS as u16, // loop
+        ]);
+        machine.keypad.press(0);
+        for _ in 0..9 {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.registers[1], 3);
+        machine.keypad.release(0);
+        for _ in 0..9 {
+            machine.execute_one().unwrap();
+        }
+        assert_eq!(machine.registers[1], 3);
+    }
+
+    #[test]
+    fn test_instr_get_key_ignores_earlier_releases() {
+        let mut machine = Machine::from_instrhex(&[0xF20A]);
+        machine.keypad.press(7);
+        machine.keypad.release(7);
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::WaitingForKey));
+        machine.keypad.press(9);
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::WaitingForKey));
+        machine.keypad.release(9);
+        assert_eq!(machine.execute_one(), Ok(StepOutcome::Executed));
+        assert_eq!(machine.registers[2], 9);
+        assert!(!machine.waiting_for_key);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut machine = Machine::from_instrhex(&[
+            0x7001,                            // v0 += 1
+            0x1000 + ROM_START_ADDRESS as u16, // loop
+        ]);
+        machine.timers.set_clock(TimerClock::Frames);
+        machine.timers.delay = 10;
+        let result = machine.run_frame(10).unwrap();
+        assert_eq!(result.instructions_executed, 10);
+        assert_eq!(result.outcome, StepOutcome::Executed);
+        assert!(!result.display_changed);
+        assert_eq!(machine.registers[0], 5);
+        assert_eq!(machine.timers.delay, 9);
+
+        for _ in 0..20 {
+            machine.run_frame(10).unwrap();
+        }
+        assert_eq!(machine.timers.delay, 0);
+        assert_eq!(machine.registers[0], 105);
+    }
+
+    #[test]
+    fn test_run_frame_display_changed() {
+        let mut machine = Machine::from_instrhex(&[0x00E0, 0x1000 + ROM_START_ADDRESS as u16 + 2]);
+        assert!(machine.run_frame(5).unwrap().display_changed);
+        assert!(!machine.run_frame(5).unwrap().display_changed);
+    }
+
+    #[test]
+    fn test_run_frame_ends_early() {
+        let mut machine = Machine::from_instrhex(&[0x6001, 0xF00A]);
+        let result = machine.run_frame(10).unwrap();
+        assert_eq!(result.instructions_executed, 2);
+        assert_eq!(result.outcome, StepOutcome::WaitingForKey);
+
+        let mut machine = Machine::from_instrhex_on(Platform::SuperChip, &[0x6001, 0x00FD]);
+        let result = machine.run_frame(10).unwrap();
+        assert_eq!(result.instructions_executed, 2);
+        assert_eq!(result.outcome, StepOutcome::Exited);
+        let result = machine.run_frame(10).unwrap();
+        assert_eq!(result.instructions_executed, 0);
+    }
+
+    #[test]
+    fn test_instr_display_clip_and_wrap() {
+        for (edges, expected) in [(SpriteEdges::Clip, false), (SpriteEdges::Wrap, true)] {
+            let mut machine = Machine::from_instrhex(&[0xD012]);
+            machine.quirks.sprite_edges = edges;
+            // Starting coordinates always wrap: (126, 63) => (62, 31)
+            machine.registers[0] = 126;
+            machine.registers[1] = 63;
+            machine.index_register = 0x400;
+            machine.ram[0x400] = 0b11110000;
+            machine.ram[0x401] = 0b10000000;
+            machine.execute_one().unwrap();
+            assert!(machine.display.pixel(62, 31));
+            assert!(machine.display.pixel(63, 31));
+            assert_eq!(machine.display.pixel(0, 31), expected);
+            assert_eq!(machine.display.pixel(1, 31), expected);
+            assert_eq!(machine.display.pixel(62, 0), expected);
+            let count = if expected { 5 } else { 2 };
+            assert_eq!(machine.display.pixels().count_value(true), count);
+        }
+    }
 }
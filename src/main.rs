@@ -1,9 +1,18 @@
 use eframe::egui::InputState;
 use eframe::emath::Align;
 use eframe::{egui, egui_glow, glow};
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use chippy8::machine::Machine;
+use chippy8::analysis::{analyze, ControlFlowGraph};
+use chippy8::clock::TimerClock;
+use chippy8::debugger::{BreakpointKind, Debugger, Hit};
+use chippy8::disassembler::{self, Syntax};
+use chippy8::machine::{Machine, MachineError, Platform};
+use chippy8::movie::{Movie, MovieError};
+use chippy8::rewind::Rewind;
+use chippy8::rom::{Rom, ROM_START_ADDRESS};
+use chippy8::savestate::SaveState;
 use chippy8::texture::RGBAImage;
 use eframe::glow::HasContext;
 use egui::mutex::Mutex;
@@ -15,6 +24,21 @@ use std::thread::{self, JoinHandle};
 use chippy8::texture::Texture;
 
 const TARGET_INSTRUCTIONS_PER_SECOND: u32 = 700;
+const FRAMES_PER_SECOND: u32 = 60;
+const INSTRUCTIONS_PER_FRAME: u32 = TARGET_INSTRUCTIONS_PER_SECOND / FRAMES_PER_SECOND;
+
+const DISPLAY_SIZE_ON_SCREEN: [f32; 2] = [640.0, 320.0];
+
+/// Where save states are written, as `<ROM name>.<slot>.state`
+const SAVES_DIRECTORY: &str = "./saves";
+/// Where movies are written, as `<ROM name>.movie.json`
+const MOVIES_DIRECTORY: &str = "./movies";
+/// Number of frames that can be rewound, i.e. 10 seconds
+const REWIND_FRAMES: usize = 600;
+/// Held to rewind, one frame per UI update
+const REWIND_KEY: egui::Key = egui::Key::Backspace;
+/// Slots are loaded with F1 to F4, and saved with Shift+F1 to Shift+F4
+const SAVE_SLOT_KEYS: [egui::Key; 4] = [egui::Key::F1, egui::Key::F2, egui::Key::F3, egui::Key::F4];
 
 fn main() -> Result<(), eframe::Error> {
     let options = eframe::NativeOptions {
@@ -39,29 +63,107 @@ enum Message {
     Exit,
 }
 
-fn machine_thread(machine: Arc<Mutex<Machine>>, rx: Receiver<Message>) {
+/// Why the machine thread paused itself
+enum Pause {
+    Error(MachineError),
+    Breakpoint(Hit),
+}
+
+/// A movie being recorded or played by the machine thread
+enum MovieSession {
+    Recording(Movie),
+    Playing { movie: Movie, frame: usize },
+}
+
+/// Record or replay the keypad of the frame about to run. Returns the outcome
+/// of the replay once the movie ended
+fn movie_before_frame(
+    session: &mut Option<MovieSession>,
+    machine: &mut Machine,
+) -> Option<Result<(), MovieError>> {
+    match session {
+        Some(MovieSession::Recording(movie)) => movie.record_frame(machine),
+        Some(MovieSession::Playing { movie, frame }) => {
+            if movie.apply_frame(*frame, machine) {
+                *frame += 1;
+            } else {
+                let outcome = movie.check_final_state(machine);
+                *session = None;
+                return Some(outcome);
+            }
+        }
+        None => {}
+    }
+    None
+}
+
+fn machine_thread(
+    machine: Arc<Mutex<Machine>>,
+    rewind: Arc<Mutex<Rewind>>,
+    movie: Arc<Mutex<Option<MovieSession>>>,
+    debugger: Arc<Mutex<Debugger>>,
+    rx: Receiver<Message>,
+    pause_tx: Sender<Pause>,
+    movie_tx: Sender<Result<(), MovieError>>,
+) {
     let mut execution_mode = ExecutionMode::Continuous;
-    let sleep_duration = Duration::new(0, 10e9 as u32 / TARGET_INSTRUCTIONS_PER_SECOND);
+    let frame_duration = Duration::from_secs(1) / FRAMES_PER_SECOND;
+    let mut next_frame = Instant::now();
     loop {
         // Handle messages if any
         let msg = rx.try_recv();
-        match msg {
+        let result = match msg {
             Ok(Message::ExecuteOne) => {
-                machine.lock().execute_one();
+                let step = debugger.lock().step(&mut machine.lock());
+                step.map(|step| {
+                    if let Some(hit) = step.hit {
+                        let _ = pause_tx.send(Pause::Breakpoint(hit));
+                    }
+                })
             }
             Ok(Message::ChangeMode(mode)) => {
                 execution_mode = mode;
+                Ok(())
             }
             Ok(Message::Exit) => break,
-            Err(_) => {}
-        }
-        // Depending on execution mode, either do next instruction
+            Err(_) => Ok(()),
+        };
+        // Depending on execution mode, either run the next frame
         // or do nothing (if step by step)
-        if execution_mode == ExecutionMode::Continuous {
-            machine.lock().execute_one();
+        let result = result.and_then(|_| {
+            if execution_mode == ExecutionMode::Continuous {
+                let mut machine = machine.lock();
+                if let Some(outcome) = movie_before_frame(&mut movie.lock(), &mut machine) {
+                    // Pause on the last frame of the movie
+                    execution_mode = ExecutionMode::StepByStep;
+                    let _ = movie_tx.send(outcome);
+                    return Ok(());
+                }
+                // Breakpoints cut frames short, which would desync movies
+                if movie.lock().is_some() {
+                    machine.run_frame(INSTRUCTIONS_PER_FRAME)?;
+                } else if let Some(hit) = debugger
+                    .lock()
+                    .run_frame(&mut machine, INSTRUCTIONS_PER_FRAME)?
+                {
+                    execution_mode = ExecutionMode::StepByStep;
+                    let _ = pause_tx.send(Pause::Breakpoint(hit));
+                    return Ok(());
+                }
+                rewind.lock().push(&machine);
+                Ok(())
+            } else {
+                Ok(())
+            }
+        });
+        // Errors pause the machine until the UI tells us what to do
+        if let Err(err) = result {
+            execution_mode = ExecutionMode::StepByStep;
+            let _ = pause_tx.send(Pause::Error(err));
         }
-        // Sleep to aim for target instructions per second
-        thread::sleep(sleep_duration);
+        // Sleep until the next frame, without trying to catch up if we're late
+        next_frame = (next_frame + frame_duration).max(Instant::now());
+        thread::sleep(next_frame - Instant::now());
     }
 }
 
@@ -69,10 +171,38 @@ struct MyApp {
     /// Behind an `Arc<Mutex<…>>` so we can pass it to [`egui::PaintCallback`] and paint later.
     display_renderer: Arc<Mutex<DisplayRenderer>>,
     machine: Arc<Mutex<Machine>>,
+    /// Snapshots of the last frames, pushed by the machine thread
+    rewind: Arc<Mutex<Rewind>>,
+    /// Whether the rewind key is held
+    rewinding: bool,
+    movie: Arc<Mutex<Option<MovieSession>>>,
+    /// Outcome of the replays, sent by the machine thread
+    movie_outcomes: Receiver<Result<(), MovieError>>,
+    movie_message: Option<String>,
+    debugger: Arc<Mutex<Debugger>>,
+    /// Breakpoint being typed in the breakpoints panel
+    breakpoint_input: String,
+    breakpoint_error: Option<String>,
+    /// Breakpoint that paused the machine last
+    last_hit: Option<Hit>,
     follow_pc: bool,
+    /// Assembly syntax used to show instructions
+    syntax: Syntax,
+    /// Static analysis of the loaded ROM, to tell code from data in memory
+    analysis: Option<ControlFlowGraph>,
     machine_thread_handle: Option<JoinHandle<()>>,
     machine_thread_tx: Sender<Message>,
+    machine_thread_pauses: Receiver<Pause>,
     execution_mode: ExecutionMode,
+    last_error: Option<MachineError>,
+    rom_load_error: Option<String>,
+    /// Path of the ROM being played
+    rom_path: Option<String>,
+    /// Outcome of the last save or load of a state
+    save_state_message: Option<String>,
+    /// Generation of the display last uploaded to the texture, reset when
+    /// the machine is replaced since a new display counts from 0 again
+    uploaded_generation: Option<u64>,
 }
 
 impl MyApp {
@@ -85,10 +215,28 @@ impl MyApp {
         let display_width = machine.display.width();
         let display_height = machine.display.height();
         let machine = Arc::new(Mutex::new(machine));
+        let rewind = Arc::new(Mutex::new(Rewind::new(REWIND_FRAMES)));
 
         let (tx, rx) = channel::<Message>();
+        let movie = Arc::new(Mutex::new(None));
+        let debugger = Arc::new(Mutex::new(Debugger::new()));
+        let (pause_tx, pause_rx) = channel::<Pause>();
+        let (movie_tx, movie_rx) = channel();
         let machine_clone = machine.clone();
-        let handle = thread::spawn(move || machine_thread(machine_clone, rx));
+        let rewind_clone = rewind.clone();
+        let movie_clone = movie.clone();
+        let debugger_clone = debugger.clone();
+        let handle = thread::spawn(move || {
+            machine_thread(
+                machine_clone,
+                rewind_clone,
+                movie_clone,
+                debugger_clone,
+                rx,
+                pause_tx,
+                movie_tx,
+            )
+        });
 
         let mut app = Self {
             display_renderer: Arc::new(Mutex::new(DisplayRenderer::new(
@@ -97,10 +245,27 @@ impl MyApp {
                 display_height,
             ))),
             machine,
+            rewind,
+            rewinding: false,
+            movie,
+            movie_outcomes: movie_rx,
+            movie_message: None,
+            debugger,
+            breakpoint_input: String::new(),
+            breakpoint_error: None,
+            last_hit: None,
             follow_pc: true,
+            syntax: Syntax::Octo,
+            analysis: None,
             machine_thread_handle: Some(handle),
             machine_thread_tx: tx,
+            machine_thread_pauses: pause_rx,
             execution_mode: ExecutionMode::Continuous,
+            last_error: None,
+            rom_load_error: None,
+            rom_path: None,
+            save_state_message: None,
+            uploaded_generation: None,
         };
         app.play_rom("roms/ibm_logo.ch8");
         app
@@ -108,11 +273,197 @@ impl MyApp {
 
     fn play_rom(&mut self, filepath: &str) {
         println!("Loading file {}", filepath);
-        *self.machine.lock() = Machine::default();
-        self.machine.lock().load_rom_from_file(filepath).unwrap();
+        let mut machine = Machine::new(Platform::from_path(filepath));
+        // Timers are decremented by the machine thread, once per frame
+        machine.timers.set_clock(TimerClock::Frames);
+        match Rom::from_file(filepath).and_then(|rom| machine.load_rom(rom)) {
+            Ok(()) => {
+                self.analysis = machine
+                    .rom
+                    .as_ref()
+                    .map(|rom| analyze(rom.bytes(), ROM_START_ADDRESS, machine.platform));
+                *self.machine.lock() = machine;
+                self.uploaded_generation = None;
+                self.rewind.lock().clear();
+                *self.movie.lock() = None;
+                self.last_error = None;
+                self.last_hit = None;
+                self.rom_load_error = None;
+                self.rom_path = Some(filepath.to_string());
+                self.save_state_message = None;
+            }
+            Err(err) => self.rom_load_error = Some(format!("{}: {}", filepath, err)),
+        }
+    }
+
+    fn set_execution_mode(&mut self, mode: ExecutionMode) {
+        self.execution_mode = mode;
+        self.machine_thread_tx
+            .send(Message::ChangeMode(mode))
+            .unwrap();
+    }
+
+    /// Whether a movie is being recorded or played, in which case the machine
+    /// state can't be changed other than by running it
+    fn movie_active(&self) -> bool {
+        self.movie.lock().is_some()
+    }
+
+    fn movie_path(&self) -> Option<PathBuf> {
+        let name = Path::new(self.rom_path.as_ref()?).file_stem()?;
+        Some(Path::new(MOVIES_DIRECTORY).join(format!("{}.movie.json", name.to_string_lossy())))
+    }
+
+    /// Restart the ROM and record from there
+    fn start_recording(&mut self) {
+        // Hold the lock so that the machine thread starts with the movie
+        let mut machine = self.machine.lock();
+        let Some(rom) = machine.rom.clone() else {
+            return;
+        };
+        let mut fresh = Machine::new(machine.platform);
+        fresh.timers.set_clock(TimerClock::Frames);
+        if let Err(err) = fresh.load_rom(rom) {
+            self.movie_message = Some(err.to_string());
+            return;
+        }
+        *self.movie.lock() = Some(MovieSession::Recording(Movie::new(
+            &fresh,
+            INSTRUCTIONS_PER_FRAME,
+        )));
+        *machine = fresh;
+        drop(machine);
+        self.uploaded_generation = None;
+        self.rewind.lock().clear();
+        self.last_error = None;
+        self.movie_message = Some("Recording".to_string());
+        self.set_execution_mode(ExecutionMode::Continuous);
+    }
+
+    fn stop_recording(&mut self) {
+        let machine = self.machine.lock();
+        let Some(MovieSession::Recording(mut movie)) = self.movie.lock().take() else {
+            return;
+        };
+        movie.finish(&machine);
+        let Some(path) = self.movie_path() else {
+            return;
+        };
+        let result = std::fs::create_dir_all(MOVIES_DIRECTORY)
+            .and_then(|_| std::fs::write(&path, movie.to_json()));
+        self.movie_message = Some(match result {
+            Ok(()) => format!(
+                "Recorded {} frames to {}",
+                movie.frames.len(),
+                path.display()
+            ),
+            Err(err) => format!("Failed to write {}: {}", path.display(), err),
+        });
+    }
+
+    /// Restart the ROM as recorded in its movie file, and replay it
+    fn start_playing(&mut self) {
+        let Some(path) = self.movie_path() else {
+            return;
+        };
+        let rom = self.machine.lock().rom.clone();
+        let result = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| Movie::from_json(&json).map_err(|err| err.to_string()))
+            .and_then(|movie| {
+                let rom = rom.ok_or("no ROM loaded")?;
+                let machine = movie.create_machine(rom).map_err(|err| err.to_string())?;
+                Ok((movie, machine))
+            });
+        match result {
+            Ok((movie, machine)) => {
+                // Hold the lock so that the machine thread starts with the movie
+                let mut current = self.machine.lock();
+                *current = machine;
+                *self.movie.lock() = Some(MovieSession::Playing { movie, frame: 0 });
+                drop(current);
+                self.uploaded_generation = None;
+                self.rewind.lock().clear();
+                self.last_error = None;
+                self.movie_message = Some(format!("Playing {}", path.display()));
+                self.set_execution_mode(ExecutionMode::Continuous);
+            }
+            Err(err) => self.movie_message = Some(format!("{}: {}", path.display(), err)),
+        }
+    }
+
+    /// Go back one frame per call while the rewind key is held, pausing the
+    /// machine, and resume when it's released
+    fn handle_rewind_key(&mut self, ctx: &egui::Context) {
+        if self.movie_active() {
+            return;
+        }
+        if ctx.input(|i| i.key_down(REWIND_KEY)) {
+            if !self.rewinding {
+                self.rewinding = true;
+                self.set_execution_mode(ExecutionMode::StepByStep);
+            }
+            let mut machine = self.machine.lock();
+            if let Err(err) = self.rewind.lock().step_back(&mut machine) {
+                self.save_state_message = Some(err.to_string());
+            }
+            ctx.request_repaint();
+        } else if self.rewinding {
+            self.rewinding = false;
+            self.last_error = None;
+            self.set_execution_mode(ExecutionMode::Continuous);
+        }
+    }
+
+    /// The file of a save slot, named after the ROM being played
+    fn save_slot_path(&self, slot: usize) -> Option<PathBuf> {
+        let name = Path::new(self.rom_path.as_ref()?).file_stem()?;
+        Some(Path::new(SAVES_DIRECTORY).join(format!("{}.{}.state", name.to_string_lossy(), slot)))
+    }
+
+    fn save_to_slot(&mut self, slot: usize) {
+        let Some(path) = self.save_slot_path(slot) else {
+            return;
+        };
+        let bytes = self.machine.lock().save_state().to_bytes();
+        let result =
+            std::fs::create_dir_all(SAVES_DIRECTORY).and_then(|_| std::fs::write(&path, bytes));
+        self.save_state_message = Some(match result {
+            Ok(()) => format!("Saved slot {}", slot),
+            Err(err) => format!("Failed to save {}: {}", path.display(), err),
+        });
+    }
+
+    fn load_from_slot(&mut self, slot: usize) {
+        if self.movie_active() {
+            self.save_state_message = Some("Can't load a state during a movie".to_string());
+            return;
+        }
+        let Some(path) = self.save_slot_path(slot) else {
+            return;
+        };
+        let state = match std::fs::read(&path) {
+            Ok(bytes) => SaveState::from_bytes(&bytes).map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let mut machine = self.machine.lock();
+        let result = state.and_then(|state| {
+            machine.load_state(&state).map_err(|err| err.to_string())?;
+            Ok(state.rom_hash)
+        });
+        let rom_hash = machine.rom.as_ref().map(|rom| rom.hash_hex());
+        self.save_state_message = Some(match result {
+            Ok(hash) if hash != rom_hash => format!("Loaded slot {}, saved with another ROM", slot),
+            Ok(_) => format!("Loaded slot {}", slot),
+            Err(err) => format!("Failed to load {}: {}", path.display(), err),
+        });
+        self.last_error = None;
     }
 }
 
+/// Extensions used by Octo for each platform
+const ROM_EXTENSIONS: [&str; 3] = [".ch8", ".sc8", ".xo8"];
+
 fn _egui_events_to_machine(i: &InputState, machine: &mut Machine) {
     for event in &i.events {
         // See keymap at https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#keypad
@@ -125,22 +476,22 @@ fn _egui_events_to_machine(i: &InputState, machine: &mut Machine) {
         {
             // TODO: This assume swiss french keyboard
             match key {
-                egui::Key::Num1 => machine.key_pressed[1] = *pressed,
-                egui::Key::Num2 => machine.key_pressed[2] = *pressed,
-                egui::Key::Num3 => machine.key_pressed[3] = *pressed,
-                egui::Key::Num4 => machine.key_pressed[0xC] = *pressed,
-                egui::Key::Q => machine.key_pressed[4] = *pressed,
-                egui::Key::W => machine.key_pressed[5] = *pressed,
-                egui::Key::E => machine.key_pressed[6] = *pressed,
-                egui::Key::R => machine.key_pressed[0xD] = *pressed,
-                egui::Key::A => machine.key_pressed[7] = *pressed,
-                egui::Key::S => machine.key_pressed[8] = *pressed,
-                egui::Key::D => machine.key_pressed[9] = *pressed,
-                egui::Key::F => machine.key_pressed[0xE] = *pressed,
-                egui::Key::Y => machine.key_pressed[0xA] = *pressed,
-                egui::Key::X => machine.key_pressed[0] = *pressed,
-                egui::Key::C => machine.key_pressed[0xB] = *pressed,
-                egui::Key::V => machine.key_pressed[0xF] = *pressed,
+                egui::Key::Num1 => machine.keypad.set(1, *pressed),
+                egui::Key::Num2 => machine.keypad.set(2, *pressed),
+                egui::Key::Num3 => machine.keypad.set(3, *pressed),
+                egui::Key::Num4 => machine.keypad.set(0xC, *pressed),
+                egui::Key::Q => machine.keypad.set(4, *pressed),
+                egui::Key::W => machine.keypad.set(5, *pressed),
+                egui::Key::E => machine.keypad.set(6, *pressed),
+                egui::Key::R => machine.keypad.set(0xD, *pressed),
+                egui::Key::A => machine.keypad.set(7, *pressed),
+                egui::Key::S => machine.keypad.set(8, *pressed),
+                egui::Key::D => machine.keypad.set(9, *pressed),
+                egui::Key::F => machine.keypad.set(0xE, *pressed),
+                egui::Key::Y => machine.keypad.set(0xA, *pressed),
+                egui::Key::X => machine.keypad.set(0, *pressed),
+                egui::Key::C => machine.keypad.set(0xB, *pressed),
+                egui::Key::V => machine.keypad.set(0xF, *pressed),
                 _ => {}
             }
         }
@@ -149,11 +500,36 @@ fn _egui_events_to_machine(i: &InputState, machine: &mut Machine) {
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Handle keyboard input
-        {
+        // The machine thread pauses itself on errors and breakpoints, reflect
+        // that in the UI
+        while let Ok(pause) = self.machine_thread_pauses.try_recv() {
+            self.execution_mode = ExecutionMode::StepByStep;
+            match pause {
+                Pause::Error(err) => self.last_error = Some(err),
+                Pause::Breakpoint(hit) => self.last_hit = Some(hit),
+            }
+        }
+        while let Ok(outcome) = self.movie_outcomes.try_recv() {
+            self.execution_mode = ExecutionMode::StepByStep;
+            self.movie_message = Some(match outcome {
+                Ok(()) => "Replay done, final state matches".to_string(),
+                Err(err) => format!("Replay done: {}", err),
+            });
+        }
+        // Handle keyboard input, which comes from the movie when playing one
+        let playing = matches!(*self.movie.lock(), Some(MovieSession::Playing { .. }));
+        if !playing {
             let mut machine = self.machine.lock();
             ctx.input(|i| _egui_events_to_machine(i, &mut machine));
         }
+        self.handle_rewind_key(ctx);
+        for (slot, key) in SAVE_SLOT_KEYS.iter().enumerate() {
+            if ctx.input_mut(|i| i.consume_key(egui::Modifiers::SHIFT, *key)) {
+                self.save_to_slot(slot + 1);
+            } else if ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, *key)) {
+                self.load_from_slot(slot + 1);
+            }
+        }
         // UI drawing
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
@@ -167,10 +543,14 @@ impl eframe::App for MyApp {
                         self.ui_registers(ui);
                     });
                     self.ui_timers(ui);
+                    self.ui_save_states(ui);
+                    self.ui_rewind(ui);
+                    self.ui_movie(ui);
                 });
                 ui.vertical(|ui| {
                     self.ui_memory(ui);
                     self.ui_keypad(ui);
+                    self.ui_breakpoints(ui);
                 })
             })
         });
@@ -189,14 +569,18 @@ impl eframe::App for MyApp {
 
 impl MyApp {
     fn ui_rom_selection(&mut self, ui: &mut egui::Ui) {
+        if let Some(err) = &self.rom_load_error {
+            ui.colored_label(egui::Color32::RED, err);
+        }
         // List all .ch8 files in the roms directory and allow to play them
         if let Ok(paths) = std::fs::read_dir("./roms") {
             ui.set_height(200.0); // TODO: A hack because as this is the first column, it will otherwise be too small
             egui::ScrollArea::vertical().show(ui, |ui| {
                 for path in paths {
                     if let Ok(path) = path {
+                        let filename = path.file_name().to_string_lossy().to_string();
                         if path.path().is_dir()
-                            || !path.file_name().to_string_lossy().ends_with(".ch8")
+                            || !ROM_EXTENSIONS.iter().any(|ext| filename.ends_with(ext))
                         {
                             continue;
                         }
@@ -241,7 +625,8 @@ impl MyApp {
                 let machine = self.machine.lock();
                 for row in keypad_layout {
                     for key in row {
-                        let _ = ui.selectable_label(machine.key_pressed[key], format!("{:X}", key));
+                        let _ = ui
+                            .selectable_label(machine.keypad.is_pressed(key), format!("{:X}", key));
                         // TODO: Could handle events through mouse click, but needs to think through the interaction
                         // with keyboard bindings
                     }
@@ -259,12 +644,17 @@ impl MyApp {
 
                 let machine = self.machine.lock();
                 ui.label(format!("pc={}", machine.program_counter));
+                if machine.halted {
+                    ui.label("Program exited");
+                }
 
                 ui.checkbox(&mut self.follow_pc, "Follow PC");
+                let rom_size = machine.rom.as_ref().map_or(0, |rom| rom.size());
 
                 let mut table = TableBuilder::new(ui)
                     .column(Column::initial(100.0))
-                    .column(Column::initial(100.0));
+                    .column(Column::initial(100.0))
+                    .column(Column::remainder());
 
                 if self.follow_pc {
                     table = table.scroll_to_row(machine.program_counter, Some(Align::Min));
@@ -277,6 +667,9 @@ impl MyApp {
                         header.col(|ui| {
                             ui.strong("Content");
                         });
+                        header.col(|ui| {
+                            ui.strong("Code");
+                        });
                     })
                     .body(|body| {
                         body.rows(text_height, machine.ram.len(), |index, mut row| {
@@ -286,12 +679,35 @@ impl MyApp {
                             row.col(|ui| {
                                 ui.label(format!("{:02x?}", machine.ram[index]));
                             });
+                            row.col(|ui| {
+                                ui.label(self.code_at(index, rom_size));
+                            });
                         });
                     });
             });
         });
     }
 
+    /// What the analysis found at `address`: the instruction starting there,
+    /// "data" for bytes that are never executed, or nothing
+    fn code_at(&self, address: usize, rom_size: usize) -> String {
+        let Some(analysis) = &self.analysis else {
+            return String::new();
+        };
+        if let Some(block) = analysis.block_containing(address) {
+            if let Some(line) = block.lines.iter().find(|line| line.address == address) {
+                return line.text(self.syntax);
+            }
+        }
+        if (ROM_START_ADDRESS..ROM_START_ADDRESS + rom_size).contains(&address)
+            && !analysis.is_code(address)
+        {
+            "data".to_string()
+        } else {
+            String::new()
+        }
+    }
+
     fn ui_registers(&mut self, ui: &mut egui::Ui) {
         ui.push_id("registers", |ui| {
             ui.vertical(|ui| {
@@ -355,8 +771,168 @@ impl MyApp {
         });
     }
 
+    fn ui_save_states(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.label("Save states (F1-F4 to load, Shift+F1-F4 to save)");
+            ui.horizontal(|ui| {
+                for slot in 1..=SAVE_SLOT_KEYS.len() {
+                    let exists = self.save_slot_path(slot).is_some_and(|path| path.exists());
+                    ui.vertical(|ui| {
+                        ui.label(format!("Slot {}", slot));
+                        if ui.button("Save").clicked() {
+                            self.save_to_slot(slot);
+                        }
+                        if ui.add_enabled(exists, egui::Button::new("Load")).clicked() {
+                            self.load_from_slot(slot);
+                        }
+                    });
+                }
+            });
+            if let Some(message) = &self.save_state_message {
+                ui.label(message);
+            }
+        });
+    }
+
+    /// Timeline of the rewind snapshots. Dragging the slider pauses and shows
+    /// the picked frame, execution then resumes from it
+    fn ui_rewind(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            let (len, position, memory_usage) = {
+                let rewind = self.rewind.lock();
+                (rewind.len(), rewind.position(), rewind.memory_usage())
+            };
+            ui.label(format!(
+                "Rewind (hold Backspace): {} frames, {} KiB",
+                len,
+                memory_usage / 1024
+            ));
+            if len == 0 {
+                return;
+            }
+            let mut index = position.unwrap_or(len - 1);
+            let slider = egui::Slider::new(&mut index, 0..=len - 1)
+                .custom_formatter(|index, _| format!("{}", index as i64 - (len - 1) as i64))
+                .text("frames");
+            if ui.add_enabled(!self.movie_active(), slider).changed() {
+                self.set_execution_mode(ExecutionMode::StepByStep);
+                let mut machine = self.machine.lock();
+                if let Err(err) = self.rewind.lock().restore(index, &mut machine) {
+                    self.save_state_message = Some(err.to_string());
+                }
+            }
+            // The newer snapshots are dropped as soon as the machine runs again
+            if position.is_some()
+                && self.execution_mode == ExecutionMode::StepByStep
+                && ui.button("Resume from here").clicked()
+            {
+                self.last_error = None;
+                self.set_execution_mode(ExecutionMode::Continuous);
+            }
+        });
+    }
+
+    fn ui_movie(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.label("Movie");
+            let status = match &*self.movie.lock() {
+                Some(MovieSession::Recording(movie)) => {
+                    Some(format!("Recording frame {}", movie.frames.len()))
+                }
+                Some(MovieSession::Playing { movie, frame }) => {
+                    Some(format!("Playing frame {}/{}", frame, movie.frames.len()))
+                }
+                None => None,
+            };
+            ui.horizontal(|ui| match status {
+                Some(status) => {
+                    ui.label(status);
+                    let recording = matches!(*self.movie.lock(), Some(MovieSession::Recording(_)));
+                    if ui.button("Stop").clicked() {
+                        if recording {
+                            self.stop_recording();
+                        } else {
+                            *self.movie.lock() = None;
+                            self.movie_message = Some("Replay stopped".to_string());
+                        }
+                    }
+                }
+                None => {
+                    if ui.button("Record").clicked() {
+                        self.start_recording();
+                    }
+                    let exists = self.movie_path().is_some_and(|path| path.exists());
+                    if ui.add_enabled(exists, egui::Button::new("Play")).clicked() {
+                        self.start_playing();
+                    }
+                }
+            });
+            if let Some(message) = &self.movie_message {
+                ui.label(message);
+            }
+        });
+    }
+
+    fn ui_breakpoints(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.label("Breakpoints (ignored during movies)");
+            ui.horizontal(|ui| {
+                let input = ui
+                    .text_edit_singleline(&mut self.breakpoint_input)
+                    .on_hover_text(
+                        "pc ADDR, read RANGE, write RANGE, equals RANGE VALUE, \
+                     reg V0-VF|I [VALUE], opcode DXYN|unknown\n\
+                     where RANGE is ADDR or START..END, e.g. write 0x300..0x310",
+                    );
+                let submitted = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Add").clicked() || submitted {
+                    match self.breakpoint_input.parse::<BreakpointKind>() {
+                        Ok(kind) => {
+                            self.debugger.lock().add(kind);
+                            self.breakpoint_input.clear();
+                            self.breakpoint_error = None;
+                        }
+                        Err(err) => self.breakpoint_error = Some(err.to_string()),
+                    }
+                }
+            });
+            if let Some(err) = &self.breakpoint_error {
+                ui.colored_label(egui::Color32::RED, err);
+            }
+            let mut debugger = self.debugger.lock();
+            let mut removed = None;
+            let mut toggled = None;
+            for breakpoint in debugger.breakpoints() {
+                ui.horizontal(|ui| {
+                    let mut enabled = breakpoint.enabled;
+                    if ui
+                        .checkbox(&mut enabled, breakpoint.kind.to_string())
+                        .changed()
+                    {
+                        toggled = Some((breakpoint.id, enabled));
+                    }
+                    if ui.small_button("Delete").clicked() {
+                        removed = Some(breakpoint.id);
+                    }
+                });
+            }
+            if let Some((id, enabled)) = toggled {
+                debugger.set_enabled(id, enabled);
+            }
+            if let Some(id) = removed {
+                debugger.remove(id);
+            }
+        });
+    }
+
     fn ui_instruction(&mut self, ui: &mut egui::Ui) {
-        let instruction = self.machine.lock().decode_next_instruction();
+        let instruction = {
+            let machine = self.machine.lock();
+            match disassembler::decode_at(&machine.ram, machine.program_counter, machine.platform) {
+                Some(line) => line.to_listing_string(self.syntax),
+                None => "Program counter out of memory".to_string(),
+            }
+        };
         ui.vertical(|ui| {
             ui.horizontal(|ui| {
                 ui.selectable_value(
@@ -370,33 +946,66 @@ impl MyApp {
                     "Continuous",
                 );
                 // TODO: Could use ui.add(egui::SelectableValue).clicked to only change this when clicked
+                if self.execution_mode == ExecutionMode::Continuous {
+                    self.last_hit = None;
+                }
                 self.machine_thread_tx
                     .send(Message::ChangeMode(self.execution_mode))
                     .unwrap();
             });
 
+            // Instructions executed outside of frames would desync movies
             if self.execution_mode == ExecutionMode::StepByStep
+                && !self.movie_active()
                 && ui.button("Execute next").clicked()
             {
+                self.last_hit = None;
                 self.machine_thread_tx.send(Message::ExecuteOne).unwrap();
             }
-            ui.label(format!("Current instruction:\n {:?}", instruction));
+            ui.horizontal(|ui| {
+                ui.label("Syntax");
+                ui.selectable_value(&mut self.syntax, Syntax::Octo, "Octo");
+                ui.selectable_value(&mut self.syntax, Syntax::Cowgod, "Cowgod");
+            });
+            ui.label(format!("Current instruction:\n {}", instruction));
+            if let Some(hit) = &self.last_hit {
+                ui.colored_label(egui::Color32::YELLOW, format!("Stopped: {}", hit));
+            }
+            if let Some(err) = self.last_error {
+                ui.colored_label(egui::Color32::RED, format!("Error: {}", err));
+                if ui.button("Skip instruction").clicked() {
+                    self.machine.lock().program_counter += 2;
+                    self.last_error = None;
+                }
+            }
         });
     }
 
     fn custom_painting(&mut self, ui: &mut egui::Ui) {
         let display_renderer = self.display_renderer.clone();
-        let image = self.machine.lock().display.to_image();
+        // Only copy and upload the image when the display changed
+        let image = {
+            let display = &mut self.machine.lock().display;
+            let generation = display.generation();
+            if self.uploaded_generation == Some(generation) {
+                None
+            } else {
+                self.uploaded_generation = Some(generation);
+                Some(display.to_image())
+            }
+        };
 
+        // Use a fixed size on screen so switching to SUPER-CHIP hires doesn't
+        // change the layout
         let (rect, _response) = ui.allocate_exact_size(
-            egui::Vec2::new(image.width() as f32 * 10.0, image.height() as f32 * 10.0),
+            egui::Vec2::new(DISPLAY_SIZE_ON_SCREEN[0], DISPLAY_SIZE_ON_SCREEN[1]),
             egui::Sense::drag(),
         );
 
         let callback = egui::PaintCallback {
             rect,
             callback: std::sync::Arc::new(egui_glow::CallbackFn::new(move |_info, painter| {
-                display_renderer.lock().paint(painter.gl(), &image);
+                display_renderer.lock().paint(painter.gl(), image.as_ref());
             })),
         };
         ui.painter().add(callback);
@@ -543,12 +1152,23 @@ impl DisplayRenderer {
             gl.delete_program(self.program);
             gl.delete_vertex_array(self.vertex_array);
         }
+        self.texture.destroy(gl);
     }
 
-    fn paint(&mut self, gl: &glow::Context, image: &RGBAImage) {
+    /// Draw the display, first uploading `image` to the texture if given
+    fn paint(&mut self, gl: &glow::Context, image: Option<&RGBAImage>) {
         use glow::HasContext as _;
+        if let Some(image) = image {
+            if image.width() != self.texture.width() || image.height() != self.texture.height() {
+                // Resolution changed (e.g. SUPER-CHIP hires), we need a new texture
+                self.texture.destroy(gl);
+                self.texture = Texture::checkerboard(gl, image.width(), image.height());
+            }
+        }
         self.texture.bind(gl, 0);
-        self.texture.update(gl, image);
+        if let Some(image) = image {
+            self.texture.update(gl, image);
+        }
         unsafe {
             gl.use_program(Some(self.program));
             gl.uniform_1_i32(gl.get_uniform_location(self.program, "diffuse").as_ref(), 0);
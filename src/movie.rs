@@ -0,0 +1,206 @@
+//! Recordings of the keypad input of a run, to replay it exactly.
+//!
+//! A movie starts from a machine that just loaded its ROM, and stores what
+//! is needed to recreate it: the ROM hash, platform, quirks and RNG seed.
+//! Then for each 60hz frame, the keypad state at the start of the frame.
+//! Replaying these on the same ROM gives the exact same run, which is checked
+//! with a hash of the final machine state.
+use crate::clock::TimerClock;
+use crate::machine::{Machine, MachineError, Platform};
+use crate::quirks::Quirks;
+use crate::rom::{Rom, RomLoadError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the movie format, bumped on any incompatible change
+pub const MOVIE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    UnsupportedVersion(u32),
+    Malformed(String),
+    /// The movie was recorded with another ROM
+    RomMismatch {
+        expected: String,
+        actual: String,
+    },
+    RomLoad(RomLoadError),
+    /// The machine raised an error during the replay
+    Machine(MachineError),
+    /// The replay didn't end in the recorded state
+    Desync {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported movie version {}, expected {}",
+                version, MOVIE_VERSION
+            ),
+            MovieError::Malformed(message) => write!(f, "malformed movie: {}", message),
+            MovieError::RomMismatch { expected, actual } => write!(
+                f,
+                "the movie was recorded with ROM {}, not {}",
+                expected, actual
+            ),
+            MovieError::RomLoad(err) => write!(f, "failed to load the ROM: {}", err),
+            MovieError::Machine(err) => write!(f, "{}", err),
+            MovieError::Desync { expected, actual } => write!(
+                f,
+                "replay desynced: final state hash is {}, expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/// The keypad at the start of a frame, as bitmasks with bit N for key N.
+/// Stored as `HELD` or `HELD/RELEASED` in hexadecimal, e.g. `0020/0001`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct FrameInput {
+    pub held: u16,
+    /// Releases not yet consumed by FX0A. Needed as a key can be pressed and
+    /// released between two frames
+    pub released: u16,
+}
+
+impl From<FrameInput> for String {
+    fn from(input: FrameInput) -> String {
+        if input.released == 0 {
+            format!("{:04x}", input.held)
+        } else {
+            format!("{:04x}/{:04x}", input.held, input.released)
+        }
+    }
+}
+
+impl TryFrom<String> for FrameInput {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (held, released) = value.split_once('/').unwrap_or((&value, "0"));
+        let parse = |mask| {
+            u16::from_str_radix(mask, 16).map_err(|_| format!("invalid frame input '{}'", value))
+        };
+        Ok(FrameInput {
+            held: parse(held)?,
+            released: parse(released)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Movie {
+    pub version: u32,
+    /// Hex SHA-1 of the ROM
+    pub rom_hash: String,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub instructions_per_frame: u32,
+    /// Keypad state of each frame, in order
+    pub frames: Vec<FrameInput>,
+    /// Hash of the machine state after the last frame, see
+    /// `SaveState::hash_hex`. Only set once the recording is finished
+    pub final_state_hash: Option<String>,
+}
+
+impl Movie {
+    /// Start recording `machine`, which must have just loaded its ROM, so
+    /// that the movie can recreate it
+    pub fn new(machine: &Machine, instructions_per_frame: u32) -> Movie {
+        Movie {
+            version: MOVIE_VERSION,
+            rom_hash: machine
+                .rom
+                .as_ref()
+                .map_or(String::new(), |rom| rom.hash_hex()),
+            platform: machine.platform,
+            quirks: machine.quirks,
+            seed: machine.rng.seed(),
+            instructions_per_frame,
+            frames: vec![],
+            final_state_hash: None,
+        }
+    }
+
+    /// Record the keypad state of the frame about to run
+    pub fn record_frame(&mut self, machine: &Machine) {
+        self.frames.push(FrameInput {
+            held: machine.keypad.held_mask(),
+            released: machine.keypad.released_mask(),
+        });
+    }
+
+    /// End the recording, storing the hash of the final state of `machine`
+    pub fn finish(&mut self, machine: &Machine) {
+        self.final_state_hash = Some(machine.save_state().hash_hex());
+    }
+
+    /// A machine in the state the recording started from, with `rom` loaded
+    pub fn create_machine(&self, rom: Rom) -> Result<Machine, MovieError> {
+        if rom.hash_hex() != self.rom_hash {
+            return Err(MovieError::RomMismatch {
+                expected: self.rom_hash.clone(),
+                actual: rom.hash_hex(),
+            });
+        }
+        let mut machine = Machine::with_seed(self.platform, self.seed);
+        machine.quirks = self.quirks;
+        machine.timers.set_clock(TimerClock::Frames);
+        machine.load_rom(rom).map_err(MovieError::RomLoad)?;
+        Ok(machine)
+    }
+
+    /// Set the keypad as it was at the start of `frame`. Returns false past
+    /// the end of the movie
+    pub fn apply_frame(&self, frame: usize, machine: &mut Machine) -> bool {
+        match self.frames.get(frame) {
+            Some(input) => {
+                machine.keypad.set_masks(input.held, input.released);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check that a replay ended in the recorded state
+    pub fn check_final_state(&self, machine: &Machine) -> Result<(), MovieError> {
+        let Some(expected) = &self.final_state_hash else {
+            return Ok(());
+        };
+        let actual = machine.save_state().hash_hex();
+        if &actual != expected {
+            return Err(MovieError::Desync {
+                expected: expected.clone(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("failed to serialize movie")
+    }
+
+    pub fn from_json(json: &str) -> Result<Movie, MovieError> {
+        #[derive(Deserialize)]
+        struct Header {
+            version: u32,
+        }
+        let header: Header =
+            serde_json::from_str(json).map_err(|err| MovieError::Malformed(err.to_string()))?;
+        if header.version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(header.version));
+        }
+        serde_json::from_str(json).map_err(|err| MovieError::Malformed(err.to_string()))
+    }
+}
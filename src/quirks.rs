@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+/// Behaviors that differ between CHIP-8 interpreters for the same opcode.
+///
+/// See https://tobiasvl.github.io/blog/write-a-chip-8-emulator/#instructions for
+/// a description of each of those ambiguities. The presets below can be used as
+/// is or tweaked per flag, e.g.
+/// `Quirks { jump_with_vx: true, ..Quirks::cosmac_vip() }`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quirks {
+    /// 8XY6/8XYE: VX is set to VY before being shifted
+    pub shift_uses_vy: bool,
+    /// BNNN: behaves as BXNN and jumps to XNN + VX instead of NNN + V0
+    pub jump_with_vx: bool,
+    /// FX55/FX65: I is incremented by X + 1 after the loads/stores
+    pub load_store_increments_index: bool,
+    /// FX1E: VF is set to 1 when I goes past 0x0FFF
+    pub add_to_index_sets_flag: bool,
+    /// 8XY1/8XY2/8XY3: VF is reset to 0 after the logic operation
+    pub logic_resets_flag: bool,
+    /// DXYN: what happens to sprite pixels going past the edges of the screen
+    pub sprite_edges: SpriteEdges,
+}
+
+/// How DXYN handles sprites crossing the right or bottom edge of the screen.
+/// In both cases, the starting coordinates themselves wrap around the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpriteEdges {
+    /// Pixels past the edge are not drawn
+    Clip,
+    /// Pixels past the edge are drawn on the opposite side
+    Wrap,
+}
+
+impl Quirks {
+    /// The original interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            jump_with_vx: false,
+            load_store_increments_index: true,
+            add_to_index_sets_flag: false,
+            logic_resets_flag: true,
+            sprite_edges: SpriteEdges::Clip,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        Self {
+            shift_uses_vy: false,
+            jump_with_vx: true,
+            load_store_increments_index: false,
+            add_to_index_sets_flag: false,
+            logic_resets_flag: false,
+            sprite_edges: SpriteEdges::Clip,
+        }
+    }
+
+    /// SUPER-CHIP 1.1, which inherited most of CHIP-48 behaviors
+    pub fn superchip() -> Self {
+        Self::chip48()
+    }
+
+    /// XO-CHIP, as implemented by Octo
+    pub fn xo_chip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            jump_with_vx: false,
+            load_store_increments_index: true,
+            add_to_index_sets_flag: false,
+            logic_resets_flag: false,
+            sprite_edges: SpriteEdges::Wrap,
+        }
+    }
+
+    /// What most modern interpreters do, and what most of the ROMs written
+    /// in the last decades expect
+    pub fn modern() -> Self {
+        Self {
+            shift_uses_vy: false,
+            jump_with_vx: false,
+            load_store_increments_index: false,
+            add_to_index_sets_flag: true,
+            logic_resets_flag: false,
+            sprite_edges: SpriteEdges::Clip,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
@@ -0,0 +1,379 @@
+//! Going back in time, by recording a snapshot of the machine every frame.
+//!
+//! Snapshots are save states whose RAM and display pixels are stored as the
+//! bytes that changed since the previous snapshot, as most frames only touch
+//! a few of them. Every `KEYFRAME_INTERVAL` snapshots, they are stored in full
+//! so that restoring one never has to go through more than that many deltas.
+use crate::array2d::Array2D;
+use crate::machine::{
+    Machine, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH, LORES_DISPLAY_HEIGHT, LORES_DISPLAY_WIDTH,
+    NUM_PLANES,
+};
+use crate::savestate::{SaveState, SaveStateError};
+use std::collections::VecDeque;
+
+/// Store RAM and pixels in full every this many snapshots
+const KEYFRAME_INTERVAL: usize = 60;
+
+/// Bytes of a snapshot, in full or as changes to the previous snapshot
+#[derive(Debug, Clone)]
+enum Data {
+    Full(Vec<u8>),
+    /// Runs of consecutive changed bytes, as (offset, new bytes)
+    Delta(Vec<(usize, Vec<u8>)>),
+}
+
+impl Data {
+    fn diff(previous: &[u8], current: &[u8]) -> Data {
+        if previous.len() != current.len() {
+            return Data::Full(current.to_vec());
+        }
+        let mut runs: Vec<(usize, Vec<u8>)> = vec![];
+        for (i, (&old, &new)) in previous.iter().zip(current).enumerate() {
+            if old == new {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((offset, bytes)) if *offset + bytes.len() == i => bytes.push(new),
+                _ => runs.push((i, vec![new])),
+            }
+        }
+        Data::Delta(runs)
+    }
+
+    /// Turn the bytes of the previous snapshot into the ones of this one
+    fn apply(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Data::Full(full) => bytes.clone_from(full),
+            Data::Delta(runs) => {
+                for (offset, run) in runs {
+                    bytes[*offset..*offset + run.len()].copy_from_slice(run);
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Data::Full(full) => full.len(),
+            Data::Delta(runs) => runs
+                .iter()
+                .map(|(_, run)| std::mem::size_of::<usize>() + run.len())
+                .sum(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    /// The save state, without its RAM and display planes
+    state: SaveState,
+    ram: Data,
+    /// All display planes, one byte per pixel
+    pixels: Data,
+}
+
+/// The last `capacity` snapshots of a machine, oldest first.
+///
+/// After restoring a snapshot, the newer ones are kept until the next push,
+/// so that one can scrub back and forth through them before resuming
+pub struct Rewind {
+    snapshots: VecDeque<Snapshot>,
+    capacity: usize,
+    /// Index of the snapshot last restored, if nothing was pushed since
+    restored: Option<usize>,
+    /// Snapshots pushed since the last keyframe
+    since_keyframe: usize,
+    /// RAM and pixels of the newest snapshot, to compute the next delta
+    last_ram: Vec<u8>,
+    last_pixels: Vec<u8>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity must be at least 1");
+        Self {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            restored: None,
+            since_keyframe: 0,
+            last_ram: vec![],
+            last_pixels: vec![],
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Index of the snapshot last restored, unless something was pushed since
+    pub fn position(&self) -> Option<usize> {
+        self.restored
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.restored = None;
+        self.since_keyframe = 0;
+    }
+
+    /// Approximate number of bytes used by the RAM and pixels of the snapshots
+    pub fn memory_usage(&self) -> usize {
+        self.snapshots
+            .iter()
+            .map(|snapshot| snapshot.ram.size() + snapshot.pixels.size())
+            .sum()
+    }
+
+    /// Record the current state of `machine` as the newest snapshot,
+    /// forgetting the oldest one if the buffer is full. If a snapshot was
+    /// restored, the ones newer than it are forgotten first
+    pub fn push(&mut self, machine: &Machine) {
+        if let Some(index) = self.restored.take() {
+            self.truncate(index);
+        }
+        let mut state = machine.save_state();
+        let ram = std::mem::take(&mut state.ram);
+        let pixels = flatten_planes(&std::mem::take(&mut state.display.planes));
+
+        let keyframe = self.snapshots.is_empty() || self.since_keyframe + 1 >= KEYFRAME_INTERVAL;
+        let snapshot = if keyframe {
+            self.since_keyframe = 0;
+            Snapshot {
+                state,
+                ram: Data::Full(ram.clone()),
+                pixels: Data::Full(pixels.clone()),
+            }
+        } else {
+            self.since_keyframe += 1;
+            Snapshot {
+                state,
+                ram: Data::diff(&self.last_ram, &ram),
+                pixels: Data::diff(&self.last_pixels, &pixels),
+            }
+        };
+        if self.snapshots.len() == self.capacity {
+            self.evict_oldest();
+        }
+        self.snapshots.push_back(snapshot);
+        self.last_ram = ram;
+        self.last_pixels = pixels;
+    }
+
+    /// Drop the oldest snapshot, storing the next one in full since it can't
+    /// be computed from the dropped one anymore
+    fn evict_oldest(&mut self) {
+        if self.snapshots.len() > 1 {
+            let ram = self.reconstruct(1, |snapshot| &snapshot.ram);
+            let pixels = self.reconstruct(1, |snapshot| &snapshot.pixels);
+            self.snapshots[1].ram = Data::Full(ram);
+            self.snapshots[1].pixels = Data::Full(pixels);
+        }
+        self.snapshots.pop_front();
+    }
+
+    /// The full bytes of `field` at snapshot `index`, starting from the
+    /// closest full snapshot before it
+    fn reconstruct(&self, index: usize, field: fn(&Snapshot) -> &Data) -> Vec<u8> {
+        let start = (0..=index)
+            .rev()
+            .find(|&i| matches!(field(&self.snapshots[i]), Data::Full(_)))
+            .expect("the oldest snapshot is always full");
+        let mut bytes = vec![];
+        for snapshot in self.snapshots.range(start..=index) {
+            field(snapshot).apply(&mut bytes);
+        }
+        bytes
+    }
+
+    /// The save state of snapshot `index`, 0 being the oldest
+    pub fn state(&self, index: usize) -> Result<SaveState, SaveStateError> {
+        let snapshot = self
+            .snapshots
+            .get(index)
+            .ok_or_else(|| SaveStateError::Invalid(format!("no snapshot {}", index)))?;
+        let mut state = snapshot.state.clone();
+        state.ram = self.reconstruct(index, |snapshot| &snapshot.ram);
+        let pixels = self.reconstruct(index, |snapshot| &snapshot.pixels);
+        let (width, height) = if state.display.hires {
+            (HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT)
+        } else {
+            (LORES_DISPLAY_WIDTH, LORES_DISPLAY_HEIGHT)
+        };
+        if pixels.len() != NUM_PLANES * width * height {
+            return Err(SaveStateError::Invalid(format!(
+                "{} pixels in snapshot {}, expected {} planes of {}x{}",
+                pixels.len(),
+                index,
+                NUM_PLANES,
+                width,
+                height
+            )));
+        }
+        state.display.planes = pixels
+            .chunks(width * height)
+            .map(|plane| Array2D::from_vec(height, width, plane.iter().map(|&p| p != 0).collect()))
+            .collect();
+        state.validate()?;
+        Ok(state)
+    }
+
+    /// Load snapshot `index` in `machine`
+    pub fn restore(&mut self, index: usize, machine: &mut Machine) -> Result<(), SaveStateError> {
+        let state = self.state(index)?;
+        machine.load_state(&state)?;
+        self.restored = Some(index);
+        Ok(())
+    }
+
+    /// Forget the snapshots newer than `index`
+    fn truncate(&mut self, index: usize) {
+        if index + 1 >= self.snapshots.len() {
+            return;
+        }
+        self.last_ram = self.reconstruct(index, |snapshot| &snapshot.ram);
+        self.last_pixels = self.reconstruct(index, |snapshot| &snapshot.pixels);
+        self.snapshots.truncate(index + 1);
+        // Distance to the last keyframe, to keep the same spacing
+        self.since_keyframe = self
+            .snapshots
+            .iter()
+            .rev()
+            .position(|snapshot| matches!(snapshot.ram, Data::Full(_)))
+            .unwrap_or(0);
+    }
+
+    /// Restore the snapshot before the one last restored, or before the
+    /// newest one. Returns false when there is nothing to go back to
+    pub fn step_back(&mut self, machine: &mut Machine) -> Result<bool, SaveStateError> {
+        let current = self
+            .restored
+            .unwrap_or_else(|| self.snapshots.len().saturating_sub(1));
+        if current == 0 {
+            return Ok(false);
+        }
+        self.restore(current - 1, machine)?;
+        Ok(true)
+    }
+}
+
+fn flatten_planes(planes: &[Array2D<bool>]) -> Vec<u8> {
+    planes
+        .iter()
+        .flat_map(|plane| plane.as_slice().iter().map(|&p| p as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::clock::TimerClock;
+    use crate::machine::Platform;
+
+    /// Moves a sprite, so RAM and display change a bit each frame
+    const PROGRAM: &str = "
+        : main
+            i := sprite
+            sprite v0 v1 2
+            v0 += 1
+            v1 += 2
+            i := counter
+            load v2
+            v2 += 1
+            save v2
+            if v0 == 40 then hires
+            jump main
+        : sprite
+            0xFF 0x81
+        : counter
+            0
+        ";
+
+    fn machine() -> Machine {
+        let mut machine = Machine::with_seed(Platform::SuperChip, 0);
+        machine.timers.set_clock(TimerClock::Frames);
+        machine
+            .load_rom_from_bytes(&assemble(PROGRAM).unwrap().bytes)
+            .unwrap();
+        machine
+    }
+
+    /// Run `frames` frames, pushing a snapshot after each, and return the
+    /// save state after each frame
+    fn record(machine: &mut Machine, rewind: &mut Rewind, frames: usize) -> Vec<SaveState> {
+        (0..frames)
+            .map(|_| {
+                machine.run_frame(10).unwrap();
+                rewind.push(machine);
+                machine.save_state()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshots_match_states() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(200);
+        let states = record(&mut machine, &mut rewind, 150);
+        assert_eq!(rewind.len(), 150);
+        for (i, state) in states.iter().enumerate() {
+            assert_eq!(rewind.state(i).ok().as_ref(), Some(state), "snapshot {}", i);
+        }
+        // Far less than storing the RAM of every frame
+        assert!(rewind.memory_usage() < 20 * 0x1000);
+
+        assert!(rewind.state(150).is_err());
+        // Pixels that don't fill the planes are an error rather than a panic
+        rewind.snapshots[0].pixels = Data::Full(vec![0; 10]);
+        assert!(matches!(rewind.state(0), Err(SaveStateError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(50);
+        let states = record(&mut machine, &mut rewind, 130);
+        assert_eq!(rewind.len(), 50);
+        for i in 0..50 {
+            assert_eq!(rewind.state(i).ok().as_ref(), Some(&states[80 + i]));
+        }
+    }
+
+    #[test]
+    fn test_rewind_and_resume() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(100);
+        let states = record(&mut machine, &mut rewind, 70);
+
+        // Scrub back and forth, then resume from frame 30
+        rewind.restore(10, &mut machine).unwrap();
+        assert_eq!(machine.save_state(), states[10]);
+        rewind.restore(30, &mut machine).unwrap();
+        assert_eq!(rewind.position(), Some(30));
+        assert_eq!(rewind.len(), 70);
+        let resumed = record(&mut machine, &mut rewind, 39);
+        assert_eq!(resumed, states[31..]);
+        assert_eq!(rewind.position(), None);
+        assert_eq!(rewind.len(), 70);
+        for (i, state) in states.iter().enumerate() {
+            assert_eq!(rewind.state(i).ok().as_ref(), Some(state));
+        }
+
+        assert!(rewind.step_back(&mut machine).unwrap());
+        assert!(rewind.step_back(&mut machine).unwrap());
+        assert_eq!(machine.save_state(), states[67]);
+        assert!(rewind.restore(70, &mut machine).is_err());
+        assert_eq!(rewind.position(), Some(67));
+        rewind.restore(0, &mut machine).unwrap();
+        assert!(!rewind.step_back(&mut machine).unwrap());
+    }
+}
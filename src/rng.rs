@@ -0,0 +1,83 @@
+use rand::{Error, RngCore, SeedableRng};
+
+/// The random number generator used by CXNN.
+///
+/// This is a SplitMix64 generator: it is tiny, fast, and its whole state is a
+/// single `u64`, which makes it easy to reproduce runs and to save/restore it.
+/// It is of course not suitable for anything cryptographic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineRng {
+    seed: u64,
+    state: u64,
+}
+
+impl MachineRng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, state: seed }
+    }
+
+    /// Seed from the OS entropy source, for when reproducibility doesn't matter
+    pub fn from_entropy() -> Self {
+        Self::new(rand::thread_rng().next_u64())
+    }
+
+    /// The seed this generator was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The current internal state, which can be given to `MachineRng::from_state`
+    /// to resume the exact same sequence
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn from_state(seed: u64, state: u64) -> Self {
+        Self { seed, state }
+    }
+}
+
+impl Default for MachineRng {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+impl RngCore for MachineRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // https://prng.di.unimi.it/splitmix64.c
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for MachineRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::new(u64::from_le_bytes(seed))
+    }
+
+    fn seed_from_u64(seed: u64) -> Self {
+        Self::new(seed)
+    }
+}
@@ -0,0 +1,114 @@
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Where programs are loaded in RAM
+pub const ROM_START_ADDRESS: usize = 0x200;
+
+/// The largest ROM any platform can run, which is XO-CHIP's 64K minus the
+/// interpreter area
+pub const MAX_ROM_SIZE: usize = 0x10000 - ROM_START_ADDRESS;
+
+#[derive(Debug)]
+pub enum RomLoadError {
+    Io(io::Error),
+    Empty,
+    /// The ROM doesn't fit in the RAM of the machine it's loaded in
+    TooLarge {
+        size: usize,
+        max_size: usize,
+    },
+}
+
+impl fmt::Display for RomLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomLoadError::Io(err) => write!(f, "failed to read ROM: {}", err),
+            RomLoadError::Empty => write!(f, "ROM is empty"),
+            RomLoadError::TooLarge { size, max_size } => write!(
+                f,
+                "ROM is {} bytes but at most {} bytes can be loaded",
+                size, max_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomLoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomLoadError {
+    fn from(err: io::Error) -> Self {
+        RomLoadError::Io(err)
+    }
+}
+
+/// A program image, as loaded from a file or from memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    bytes: Vec<u8>,
+    path: Option<PathBuf>,
+    hash: [u8; 20],
+}
+
+impl Rom {
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Rom, RomLoadError> {
+        if bytes.is_empty() {
+            return Err(RomLoadError::Empty);
+        }
+        if bytes.len() > MAX_ROM_SIZE {
+            return Err(RomLoadError::TooLarge {
+                size: bytes.len(),
+                max_size: MAX_ROM_SIZE,
+            });
+        }
+        let hash = Sha1::digest(&bytes).into();
+        Ok(Rom {
+            bytes,
+            path: None,
+            hash,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Rom, RomLoadError> {
+        let mut f = File::open(path.as_ref())?;
+        let mut buf: Vec<u8> = vec![];
+        f.read_to_end(&mut buf)?;
+        Ok(Rom {
+            path: Some(path.as_ref().to_path_buf()),
+            ..Rom::from_bytes(buf)?
+        })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The file this ROM was read from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// SHA-1 of the content, which is what the CHIP-8 community uses to
+    /// identify ROMs (e.g. in https://github.com/chip-8/chip-8-database)
+    pub fn hash(&self) -> &[u8; 20] {
+        &self.hash
+    }
+
+    pub fn hash_hex(&self) -> String {
+        self.hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
@@ -0,0 +1,498 @@
+//! Snapshots of the whole machine state, to restore it later.
+//!
+//! A `SaveState` is taken with `Machine::save_state` and restored with
+//! `Machine::load_state`. It can be stored either in a compact binary form or
+//! as JSON, both starting with the format version so that states written by
+//! an incompatible version are rejected rather than misread.
+use crate::array2d::Array2D;
+use crate::keypad::Keypad;
+use crate::machine::{
+    Palette, Platform, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH, LORES_DISPLAY_HEIGHT,
+    LORES_DISPLAY_WIDTH, NUM_PLANES,
+};
+use crate::quirks::Quirks;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+/// Version of the save state format, bumped on any incompatible change
+pub const SAVE_STATE_VERSION: u32 = 1;
+
+/// First bytes of the binary form, followed by the version as a little
+/// endian u32
+const MAGIC: &[u8; 4] = b"C8SS";
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    /// The state was written by another version of the format
+    UnsupportedVersion(u32),
+    /// Not a save state, or a truncated or corrupted one
+    Malformed(String),
+    /// The state is well formed but inconsistent, e.g. RAM of the wrong size
+    Invalid(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported save state version {}, expected {}",
+                version, SAVE_STATE_VERSION
+            ),
+            SaveStateError::Malformed(message) => write!(f, "malformed save state: {}", message),
+            SaveStateError::Invalid(message) => write!(f, "invalid save state: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+/// Delay and sound timers, with what was accumulated towards their next
+/// decrement. The `TimerClock` itself isn't saved: the restored timers keep
+/// the clock of the machine they're loaded in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimersState {
+    pub delay: u8,
+    pub sound: u8,
+    /// Fraction of a 60hz tick elapsed, for `TimerClock::Time`
+    pub tick_remainder: f64,
+    /// Instructions executed since the last decrement, for `TimerClock::Cycles`
+    pub cycles_since_tick: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisplayState {
+    pub hires: bool,
+    pub selected_planes: u8,
+    pub palette: Palette,
+    /// One entry per plane. As JSON, each plane is a list of rows drawn with
+    /// `.` and `#`, in binary the pixels are packed 8 per byte
+    #[serde(with = "planes")]
+    pub planes: Vec<Array2D<bool>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveState {
+    /// Always `SAVE_STATE_VERSION` for states created by this version
+    pub version: u32,
+    pub platform: Platform,
+    /// Hex SHA-1 of the ROM that was loaded, if any. RAM is saved as a whole
+    /// so this is only informational, e.g. to warn about a state taken with
+    /// another ROM
+    pub rom_hash: Option<String>,
+    /// As JSON, lines of 32 bytes in hexadecimal
+    #[serde(with = "hex_lines")]
+    pub ram: Vec<u8>,
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    pub program_counter: usize,
+    /// The whole stack, including the unused entries past `stack_index`
+    pub stack: Vec<u16>,
+    pub stack_index: usize,
+    pub timers: TimersState,
+    pub display: DisplayState,
+    pub keypad: Keypad,
+    pub waiting_for_key: bool,
+    pub rpl_flags: [u8; 16],
+    pub halted: bool,
+    pub rng_seed: u64,
+    pub rng_state: u64,
+    pub quirks: Quirks,
+}
+
+impl SaveState {
+    /// The compact binary form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        // Serializing can only fail for unsupported types, which we don't use
+        bytes.extend(bincode::serialize(self).expect("failed to serialize save state"));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SaveStateError> {
+        let body = bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| SaveStateError::Malformed("missing header".to_string()))?;
+        // The version is the first field, check it before decoding the rest
+        let version = body
+            .get(..4)
+            .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+            .ok_or_else(|| SaveStateError::Malformed("missing version".to_string()))?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        bincode::deserialize(body).map_err(|err| SaveStateError::Malformed(err.to_string()))
+    }
+
+    /// Hex SHA-1 of the binary form, to check that two machines are in the
+    /// exact same state
+    pub fn hash_hex(&self) -> String {
+        Sha1::digest(self.to_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// The human readable form, as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("failed to serialize save state")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, SaveStateError> {
+        #[derive(Deserialize)]
+        struct Header {
+            version: u32,
+        }
+        let header: Header =
+            serde_json::from_str(json).map_err(|err| SaveStateError::Malformed(err.to_string()))?;
+        if header.version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(header.version));
+        }
+        serde_json::from_str(json).map_err(|err| SaveStateError::Malformed(err.to_string()))
+    }
+
+    /// Check that the state is consistent, so it can be loaded without
+    /// ending up with a machine that panics
+    pub fn validate(&self) -> Result<(), SaveStateError> {
+        let invalid = |message: String| Err(SaveStateError::Invalid(message));
+        if self.ram.len() != self.platform.ram_size() {
+            return invalid(format!(
+                "{} bytes of RAM, expected {} for {:?}",
+                self.ram.len(),
+                self.platform.ram_size(),
+                self.platform
+            ));
+        }
+        if self.stack_index > self.stack.len() {
+            return invalid(format!(
+                "stack index {} past the end of the stack ({} entries)",
+                self.stack_index,
+                self.stack.len()
+            ));
+        }
+        let display = &self.display;
+        let (width, height) = if display.hires {
+            (HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT)
+        } else {
+            (LORES_DISPLAY_WIDTH, LORES_DISPLAY_HEIGHT)
+        };
+        if display.planes.len() != NUM_PLANES {
+            return invalid(format!(
+                "{} display planes, expected {}",
+                display.planes.len(),
+                NUM_PLANES
+            ));
+        }
+        if display
+            .planes
+            .iter()
+            .any(|plane| plane.cols() != width || plane.rows() != height)
+        {
+            return invalid(format!("display planes aren't {}x{}", width, height));
+        }
+        let tick_remainder = self.timers.tick_remainder;
+        if !(0.0..1.0).contains(&tick_remainder) {
+            return invalid(format!(
+                "timer tick remainder {} outside of [0, 1)",
+                tick_remainder
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `Vec<u8>` as lines of hexadecimal in human readable formats
+mod hex_lines {
+    use super::*;
+
+    const BYTES_PER_LINE: usize = 32;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return bytes.serialize(serializer);
+        }
+        let lines: Vec<String> = bytes
+            .chunks(BYTES_PER_LINE)
+            .map(|chunk| chunk.iter().map(|b| format!("{:02x}", b)).collect())
+            .collect();
+        lines.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Vec::deserialize(deserializer);
+        }
+        let lines = Vec::<String>::deserialize(deserializer)?;
+        let mut bytes = vec![];
+        for line in lines {
+            if line.len() % 2 != 0 || !line.is_ascii() {
+                return Err(D::Error::custom(format!("invalid hex line '{}'", line)));
+            }
+            for i in (0..line.len()).step_by(2) {
+                let byte = u8::from_str_radix(&line[i..i + 2], 16)
+                    .map_err(|_| D::Error::custom(format!("invalid hex line '{}'", line)))?;
+                bytes.push(byte);
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// Display planes as `.`/`#` rows in human readable formats, and as
+/// `(rows, cols, packed bits)` otherwise
+mod planes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        planes: &[Array2D<bool>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let planes: Vec<Vec<String>> = planes
+                .iter()
+                .map(|plane| {
+                    (0..plane.rows())
+                        .map(|i| {
+                            (0..plane.cols())
+                                .map(|j| if plane[(i, j)] { '#' } else { '.' })
+                                .collect()
+                        })
+                        .collect()
+                })
+                .collect();
+            planes.serialize(serializer)
+        } else {
+            let planes: Vec<(usize, usize, Vec<u8>)> = planes
+                .iter()
+                .map(|plane| {
+                    let packed = plane
+                        .as_slice()
+                        .chunks(8)
+                        .map(|pixels| {
+                            pixels
+                                .iter()
+                                .enumerate()
+                                .map(|(i, &p)| (p as u8) << (7 - i))
+                                .sum()
+                        })
+                        .collect();
+                    (plane.rows(), plane.cols(), packed)
+                })
+                .collect();
+            planes.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Array2D<bool>>, D::Error> {
+        let mut planes = vec![];
+        if deserializer.is_human_readable() {
+            for rows in Vec::<Vec<String>>::deserialize(deserializer)? {
+                let cols = rows.first().map_or(0, |row| row.chars().count());
+                let mut plane = Array2D::new(rows.len(), cols, || false);
+                for (i, row) in rows.iter().enumerate() {
+                    if row.chars().count() != cols {
+                        return Err(D::Error::custom("display rows of different widths"));
+                    }
+                    for (j, c) in row.chars().enumerate() {
+                        plane[(i, j)] = match c {
+                            '#' => true,
+                            '.' => false,
+                            _ => return Err(D::Error::custom(format!("invalid pixel '{}'", c))),
+                        };
+                    }
+                }
+                planes.push(plane);
+            }
+        } else {
+            for (rows, cols, packed) in Vec::<(usize, usize, Vec<u8>)>::deserialize(deserializer)? {
+                // Check the size before allocating, it comes straight from the input
+                if rows > HIRES_DISPLAY_HEIGHT || cols > HIRES_DISPLAY_WIDTH {
+                    return Err(D::Error::custom(format!(
+                        "{}x{} display plane is too large",
+                        cols, rows
+                    )));
+                }
+                if rows
+                    .checked_mul(cols)
+                    .is_none_or(|pixels| packed.len() * 8 < pixels)
+                {
+                    return Err(D::Error::custom("truncated display plane"));
+                }
+                let mut plane = Array2D::new(rows, cols, || false);
+                for i in 0..rows {
+                    for j in 0..cols {
+                        let n = i * cols + j;
+                        plane[(i, j)] = (packed[n / 8] >> (7 - n % 8)) & 1 == 1;
+                    }
+                }
+                planes.push(plane);
+            }
+        }
+        Ok(planes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TimerClock;
+    use crate::machine::Machine;
+
+    /// Timers tick every few instructions, so runs are reproducible
+    fn reproducible_machine(source: &str) -> Machine {
+        let mut machine = Machine::from_octo(Platform::XoChip, source);
+        machine.timers.set_clock(TimerClock::Cycles {
+            instructions_per_tick: 3,
+        });
+        machine
+    }
+
+    const DRAW_RANDOM: &str = "
+        : main
+            hires
+            plane 3
+            v0 := random 0x7F
+            v1 := random 0x3F
+            i := sprite
+            sprite v0 v1 1
+            delay := v0
+            sub
+            jump main
+        : sub
+            return
+        : sprite
+            0xA5
+        ";
+
+    fn run(machine: &mut Machine, instructions: usize) {
+        for _ in 0..instructions {
+            machine.execute_one().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let mut machine = reproducible_machine(DRAW_RANDOM);
+        machine.keypad.press(0xA);
+        run(&mut machine, 25);
+        let state = machine.save_state();
+        assert_eq!(SaveState::from_bytes(&state.to_bytes()).unwrap(), state);
+        assert_eq!(SaveState::from_json(&state.to_json()).unwrap(), state);
+        // Planes are packed in binary, so this is mostly RAM
+        assert!(state.to_bytes().len() < Platform::XoChip.ram_size() + 4096);
+    }
+
+    #[test]
+    fn test_resume_from_state() {
+        let mut machine = reproducible_machine(DRAW_RANDOM);
+        run(&mut machine, 25);
+        let state = machine.save_state();
+        run(&mut machine, 100);
+
+        // A fresh machine with another seed and timer phase ends up identical
+        let mut restored = Machine::with_seed(Platform::Chip8, 1);
+        restored.timers.set_clock(TimerClock::Cycles {
+            instructions_per_tick: 3,
+        });
+        restored.load_state(&state).unwrap();
+        run(&mut restored, 100);
+        let (expected, actual) = (machine.save_state(), restored.save_state());
+        assert_eq!(actual.rom_hash, None);
+        assert_eq!(
+            SaveState {
+                rom_hash: expected.rom_hash.clone(),
+                ..actual
+            },
+            expected
+        );
+    }
+
+    #[test]
+    fn test_load_state_invalidates_decode_cache() {
+        let mut machine = reproducible_machine(": main v0 := 1 jump main");
+        let mut state = machine.save_state();
+        state.ram[0x201] = 2;
+        run(&mut machine, 2);
+        machine.load_state(&state).unwrap();
+        run(&mut machine, 1);
+        assert_eq!(machine.registers[0], 2);
+    }
+
+    #[test]
+    fn test_json_is_readable() {
+        let mut machine =
+            reproducible_machine(": main v3 := 0x12 i := sprite sprite v0 v0 1 : sprite 0xC0");
+        run(&mut machine, 3);
+        let json = machine.save_state().to_json();
+        assert!(json.contains("\"version\": 1,"));
+        assert!(json.contains("\"platform\": \"XoChip\","));
+        assert!(json.contains("\"##......"));
+        assert!(json.contains("\"6312a206d001c0000000"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let state = Machine::new(Platform::Chip8).save_state();
+        let mut bytes = state.to_bytes();
+        bytes[4] = 2;
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            SaveState::from_bytes(b"C8"),
+            Err(SaveStateError::Malformed(_))
+        ));
+        assert!(matches!(
+            SaveState::from_bytes(&state.to_bytes()[..100]),
+            Err(SaveStateError::Malformed(_))
+        ));
+        let json = state.to_json().replace("\"version\": 1", "\"version\": 0");
+        assert!(matches!(
+            SaveState::from_json(&json),
+            Err(SaveStateError::UnsupportedVersion(0))
+        ));
+
+        let mut machine = Machine::new(Platform::Chip8);
+        let invalid = SaveState {
+            platform: Platform::XoChip,
+            ..state.clone()
+        };
+        assert_eq!(
+            machine.load_state(&invalid).unwrap_err().to_string(),
+            "invalid save state: 4096 bytes of RAM, expected 65536 for XoChip"
+        );
+        let invalid = SaveState {
+            stack_index: 101,
+            ..state.clone()
+        };
+        assert!(machine.load_state(&invalid).is_err());
+        for tick_remainder in [-0.5, 1.0, f64::NAN, f64::INFINITY] {
+            let mut invalid = state.clone();
+            invalid.timers.tick_remainder = tick_remainder;
+            assert!(matches!(
+                machine.load_state(&invalid),
+                Err(SaveStateError::Invalid(_))
+            ));
+        }
+
+        // Display planes claiming 2^32 x 2^32 pixels, patched over the 32 rows
+        // and 64 columns stored as little endian u64s
+        let mut bytes = state.to_bytes();
+        let dims = [&32u64.to_le_bytes()[..], &64u64.to_le_bytes()[..]].concat();
+        let offset = bytes
+            .windows(dims.len())
+            .position(|window| window == dims)
+            .unwrap();
+        let huge = [(1u64 << 32).to_le_bytes(), (1u64 << 32).to_le_bytes()].concat();
+        bytes[offset..offset + huge.len()].copy_from_slice(&huge);
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+//! Run the bundled ROMs headlessly and compare their screen with golden
+//! bitmaps in `tests/golden`, stored as ASCII art with `#` for lit pixels.
+//! Run with `UPDATE_GOLDEN=1` to write the current screens as new goldens.
+use chippy8::dump::display_to_ascii;
+use chippy8::headless::{run, KeyScript, RunLength};
+use chippy8::machine::{Machine, Platform};
+use chippy8::rom::Rom;
+use std::path::PathBuf;
+
+const FRAMES: u64 = 120;
+const INSTRUCTIONS_PER_FRAME: u32 = 11;
+
+fn repo_path(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+fn run_rom(name: &str) -> Machine {
+    let path = repo_path("roms").join(name);
+    let rom = Rom::from_file(&path).unwrap();
+    let mut machine = Machine::with_seed(Platform::from_path(&path), 0);
+    machine.load_rom(rom).unwrap();
+    run(
+        &mut machine,
+        RunLength::Frames(FRAMES),
+        INSTRUCTIONS_PER_FRAME,
+        &KeyScript::default(),
+    )
+    .unwrap();
+    machine
+}
+
+/// Expected and actual screens side by side, with differing rows marked
+fn ascii_diff(expected: &[Vec<bool>], actual: &[Vec<bool>]) -> String {
+    let row_to_string = |row: Option<&Vec<bool>>| -> String {
+        row.map_or(String::new(), |row| {
+            row.iter().map(|&p| if p { '#' } else { '.' }).collect()
+        })
+    };
+    let mut out = String::from("expected | actual\n");
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        let marker = if e == a { "" } else { " <" };
+        out += &format!("{} | {}{}\n", row_to_string(e), row_to_string(a), marker);
+    }
+    out
+}
+
+fn check_golden(rom_name: &str) {
+    let machine = run_rom(rom_name);
+    let pixels = machine.display.pixels();
+    let actual: Vec<Vec<bool>> = (0..pixels.rows())
+        .map(|i| (0..pixels.cols()).map(|j| pixels[(i, j)]).collect())
+        .collect();
+
+    let golden_path = repo_path("tests/golden").join(rom_name.replace(".ch8", ".txt"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&golden_path, display_to_ascii(&machine.display)).unwrap();
+        return;
+    }
+    let golden = std::fs::read_to_string(&golden_path)
+        .unwrap_or_else(|err| panic!("{}: {}", golden_path.display(), err));
+    let expected: Vec<Vec<bool>> = golden
+        .lines()
+        .map(|line| line.chars().map(|c| c == '#').collect())
+        .collect();
+
+    if expected != actual {
+        let differing = expected
+            .iter()
+            .flatten()
+            .zip(actual.iter().flatten())
+            .filter(|(e, a)| e != a)
+            .count();
+        panic!(
+            "{} doesn't match {} ({} pixels differ)\n{}",
+            rom_name,
+            golden_path.display(),
+            differing,
+            ascii_diff(&expected, &actual)
+        );
+    }
+}
+
+#[test]
+fn test_ibm_logo() {
+    check_golden("ibm_logo.ch8");
+}
+
+#[test]
+fn test_chip8_picture() {
+    check_golden("Chip8 Picture.ch8");
+}
+
+#[test]
+fn test_opcode_screen() {
+    check_golden("test_opcode.ch8");
+}
+
+#[test]
+fn test_octo_default() {
+    check_golden("octo_default.ch8");
+}
+
+#[test]
+fn test_octo_keyboard() {
+    check_golden("octo_keyboard.ch8");
+}
+
+#[test]
+fn test_octo_slippery() {
+    check_golden("octo_slippery.ch8");
+}
+
+/// The "OK" mark drawn by test_opcode.ch8 next to each passing opcode
+const OK_MARK: [&str; 4] = ["###.#.#", "#.#.##.", "#.#.#.#", "###.#.#"];
+
+/// Opcodes checked by test_opcode.ch8, laid out in 3 columns on screen, see
+/// https://github.com/corax89/chip8-test-rom
+const TESTED_OPCODES: [[&str; 3]; 6] = [
+    ["3XNN", "00EE", "8XY5"],
+    ["4XNN", "8XY0", "8XY6"],
+    ["5XY0", "8XY1", "8XYE"],
+    ["7XNN", "8XY2", "FX55"],
+    ["9XY0", "8XY3", "FX33"],
+    ["ANNN", "8XY4", "FX1E"],
+];
+
+/// Where the mark of each column starts
+const MARK_COLUMNS: [usize; 3] = [10, 32, 52];
+
+#[test]
+fn test_opcode_marks() {
+    let machine = run_rom("test_opcode.ch8");
+    let ascii = display_to_ascii(&machine.display);
+    let rows: Vec<&str> = ascii.lines().collect();
+    let mut failures = vec![];
+    for (i, opcodes) in TESTED_OPCODES.iter().enumerate() {
+        for (opcode, x) in opcodes.iter().zip(MARK_COLUMNS) {
+            let y = 1 + 5 * i;
+            let mark: Vec<&str> = (y..y + 4).map(|y| &rows[y][x..x + 7]).collect();
+            if mark != OK_MARK {
+                failures.push(format!("{}:\n{}", opcode, mark.join("\n")));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "opcodes not marked OK:\n{}",
+        failures.join("\n")
+    );
+}